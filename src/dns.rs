@@ -1,24 +1,42 @@
 use std::borrow::Cow;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr, SocketAddrV6};
+#[cfg(any(feature = "cloudflare", feature = "google", feature = "opendns"))]
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::pin::Pin;
 use std::str;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
 use futures_core::Stream;
-use futures_util::{future, ready, stream, StreamExt};
+use futures_util::{future, ready, stream, StreamExt, TryStreamExt};
 use hickory_proto::{
     error::{ProtoError, ProtoErrorKind},
-    op::Query,
-    rr::{Name, RData, RecordType},
+    iocompat::AsyncIoTokioAsStd,
+    op::{Edns, Message, MessageType, OpCode, Query, ResponseCode},
+    rr::{
+        rdata::opt::{EdnsCode, EdnsOption},
+        Name, RData, Record, RecordType,
+    },
+    tcp::TcpClientStream,
     udp::UdpClientStream,
-    xfer::{DnsHandle, DnsRequestOptions, DnsResponse},
+    xfer::{DnsHandle, DnsRequest, DnsRequestOptions, DnsResponse},
 };
 use pin_project_lite::pin_project;
-use tracing::trace_span;
+use thiserror::Error;
+use tracing::{trace, trace_span};
 use tracing_futures::Instrument;
 
 use hickory_client::{client::AsyncClient, rr::DNSClass};
-use tokio::{net::UdpSocket, runtime::Handle};
+use tokio::{
+    net::{TcpStream, UdpSocket},
+    runtime::Handle,
+};
 
 use crate::{Resolutions, Version};
 
@@ -26,6 +44,15 @@ use crate::{Resolutions, Version};
 // Hardcoded resolvers
 
 const DEFAULT_DNS_PORT: u16 = 53;
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_PAYLOAD_LEN: u16 = 1232;
+const GOOGLE_WHOAMI_NAME: &str = "o-o.myaddr.l.google.com";
+#[cfg(feature = "cloudflare")]
+const CLOUDFLARE_WHOAMI_NAME: &str = "whoami.cloudflare";
+#[cfg(feature = "akamai")]
+const AKAMAI_WHOAMI_NAME: &str = "whoami.akamai.net";
+#[cfg(feature = "opendns")]
+const OPENDNS_DEBUG_NAME: &str = "debug.opendns.com";
 
 /// All builtin DNS resolvers.
 pub const ALL: &dyn crate::Resolver<'static> = &&[
@@ -35,6 +62,10 @@ pub const ALL: &dyn crate::Resolver<'static> = &&[
     GOOGLE,
     #[cfg(feature = "cloudflare")]
     CLOUDFLARE,
+    #[cfg(feature = "akamai")]
+    AKAMAI,
+    #[cfg(feature = "opendns")]
+    OPENDNS_DEBUG,
 ];
 
 /// Combined OpenDNS IPv4 and IPv6 options.
@@ -47,13 +78,7 @@ pub const OPENDNS: &dyn crate::Resolver<'static> = &&[OPENDNS_V4, OPENDNS_V6];
 #[cfg_attr(docsrs, doc(cfg(feature = "opendns")))]
 pub const OPENDNS_V4: &dyn crate::Resolver<'static> = &Resolver::new_static(
     "myip.opendns.com",
-    &[
-        IpAddr::V4(Ipv4Addr::new(208, 67, 222, 222)),
-        IpAddr::V4(Ipv4Addr::new(208, 67, 220, 220)),
-        IpAddr::V4(Ipv4Addr::new(208, 67, 222, 220)),
-        IpAddr::V4(Ipv4Addr::new(208, 67, 220, 222)),
-    ],
-    DEFAULT_DNS_PORT,
+    OPENDNS_V4_SERVERS,
     QueryMethod::A,
     DNSClass::IN,
 );
@@ -63,17 +88,63 @@ pub const OPENDNS_V4: &dyn crate::Resolver<'static> = &Resolver::new_static(
 #[cfg_attr(docsrs, doc(cfg(feature = "opendns")))]
 pub const OPENDNS_V6: &dyn crate::Resolver<'static> = &Resolver::new_static(
     "myip.opendns.com",
-    &[
-        // 2620:0:ccc::2
-        IpAddr::V6(Ipv6Addr::new(9760, 0, 3276, 0, 0, 0, 0, 2)),
-        // 2620:0:ccd::2
-        IpAddr::V6(Ipv6Addr::new(9760, 0, 3277, 0, 0, 0, 0, 2)),
-    ],
-    DEFAULT_DNS_PORT,
+    OPENDNS_V6_SERVERS,
     QueryMethod::AAAA,
     DNSClass::IN,
 );
 
+/// Combined OpenDNS debug IPv4 and IPv6 options.
+///
+/// These query the `debug.opendns.com` `TXT` interface, which reports the
+/// source address and port of the query along with the OpenDNS site that
+/// answered it.
+#[cfg(feature = "opendns")]
+#[cfg_attr(docsrs, doc(cfg(feature = "opendns")))]
+pub const OPENDNS_DEBUG: &dyn crate::Resolver<'static> = &&[OPENDNS_DEBUG_V4, OPENDNS_DEBUG_V6];
+
+/// OpenDNS debug IPv4 DNS resolver options.
+#[cfg(feature = "opendns")]
+#[cfg_attr(docsrs, doc(cfg(feature = "opendns")))]
+pub const OPENDNS_DEBUG_V4: &dyn crate::Resolver<'static> = &Resolver::new_static(
+    OPENDNS_DEBUG_NAME,
+    OPENDNS_V4_SERVERS,
+    QueryMethod::Custom(
+        RecordType::TXT,
+        Extractor::new_static(&opendns_debug_source),
+    ),
+    DNSClass::IN,
+);
+
+/// OpenDNS debug IPv6 DNS resolver options.
+#[cfg(feature = "opendns")]
+#[cfg_attr(docsrs, doc(cfg(feature = "opendns")))]
+pub const OPENDNS_DEBUG_V6: &dyn crate::Resolver<'static> = &Resolver::new_static(
+    OPENDNS_DEBUG_NAME,
+    OPENDNS_V6_SERVERS,
+    QueryMethod::Custom(
+        RecordType::TXT,
+        Extractor::new_static(&opendns_debug_source),
+    ),
+    DNSClass::IN,
+);
+
+/// OpenDNS's public recursive resolvers.
+#[cfg(feature = "opendns")]
+const OPENDNS_V4_SERVERS: &[Server<'static>] = &[
+    Server::ip(IpAddr::V4(Ipv4Addr::new(208, 67, 222, 222))),
+    Server::ip(IpAddr::V4(Ipv4Addr::new(208, 67, 220, 220))),
+    Server::ip(IpAddr::V4(Ipv4Addr::new(208, 67, 222, 220))),
+    Server::ip(IpAddr::V4(Ipv4Addr::new(208, 67, 220, 222))),
+];
+
+#[cfg(feature = "opendns")]
+const OPENDNS_V6_SERVERS: &[Server<'static>] = &[
+    // 2620:0:ccc::2
+    Server::ip(IpAddr::V6(Ipv6Addr::new(9760, 0, 3276, 0, 0, 0, 0, 2))),
+    // 2620:0:ccd::2
+    Server::ip(IpAddr::V6(Ipv6Addr::new(9760, 0, 3277, 0, 0, 0, 0, 2))),
+];
+
 /// Combined Google DNS IPv4 and IPv6 options
 #[cfg(feature = "google")]
 #[cfg_attr(docsrs, doc(cfg(feature = "google")))]
@@ -83,14 +154,8 @@ pub const GOOGLE: &dyn crate::Resolver<'static> = &&[GOOGLE_V4, GOOGLE_V6];
 #[cfg(feature = "google")]
 #[cfg_attr(docsrs, doc(cfg(feature = "google")))]
 pub const GOOGLE_V4: &dyn crate::Resolver<'static> = &Resolver::new_static(
-    "o-o.myaddr.l.google.com",
-    &[
-        IpAddr::V4(Ipv4Addr::new(216, 239, 32, 10)),
-        IpAddr::V4(Ipv4Addr::new(216, 239, 34, 10)),
-        IpAddr::V4(Ipv4Addr::new(216, 239, 36, 10)),
-        IpAddr::V4(Ipv4Addr::new(216, 239, 38, 10)),
-    ],
-    DEFAULT_DNS_PORT,
+    GOOGLE_WHOAMI_NAME,
+    GOOGLE_V4_SERVERS,
     QueryMethod::TXT,
     DNSClass::IN,
 );
@@ -99,22 +164,41 @@ pub const GOOGLE_V4: &dyn crate::Resolver<'static> = &Resolver::new_static(
 #[cfg(feature = "google")]
 #[cfg_attr(docsrs, doc(cfg(feature = "google")))]
 pub const GOOGLE_V6: &dyn crate::Resolver<'static> = &Resolver::new_static(
-    "o-o.myaddr.l.google.com",
-    &[
-        // 2001:4860:4802:32::a
-        IpAddr::V6(Ipv6Addr::new(8193, 18528, 18434, 50, 0, 0, 0, 10)),
-        // 2001:4860:4802:34::a
-        IpAddr::V6(Ipv6Addr::new(8193, 18528, 18434, 52, 0, 0, 0, 10)),
-        // 2001:4860:4802:36::a
-        IpAddr::V6(Ipv6Addr::new(8193, 18528, 18434, 54, 0, 0, 0, 10)),
-        // 2001:4860:4802:38::a
-        IpAddr::V6(Ipv6Addr::new(8193, 18528, 18434, 56, 0, 0, 0, 10)),
-    ],
-    DEFAULT_DNS_PORT,
+    GOOGLE_WHOAMI_NAME,
+    GOOGLE_V6_SERVERS,
     QueryMethod::TXT,
     DNSClass::IN,
 );
 
+/// Google's authoritative name servers for `l.google.com`.
+#[cfg(feature = "google")]
+const GOOGLE_V4_SERVERS: &[Server<'static>] = &[
+    Server::ip(IpAddr::V4(Ipv4Addr::new(216, 239, 32, 10))),
+    Server::ip(IpAddr::V4(Ipv4Addr::new(216, 239, 34, 10))),
+    Server::ip(IpAddr::V4(Ipv4Addr::new(216, 239, 36, 10))),
+    Server::ip(IpAddr::V4(Ipv4Addr::new(216, 239, 38, 10))),
+];
+
+#[cfg(feature = "google")]
+const GOOGLE_V6_SERVERS: &[Server<'static>] = &[
+    // 2001:4860:4802:32::a
+    Server::ip(IpAddr::V6(Ipv6Addr::new(
+        8193, 18528, 18434, 50, 0, 0, 0, 10,
+    ))),
+    // 2001:4860:4802:34::a
+    Server::ip(IpAddr::V6(Ipv6Addr::new(
+        8193, 18528, 18434, 52, 0, 0, 0, 10,
+    ))),
+    // 2001:4860:4802:36::a
+    Server::ip(IpAddr::V6(Ipv6Addr::new(
+        8193, 18528, 18434, 54, 0, 0, 0, 10,
+    ))),
+    // 2001:4860:4802:38::a
+    Server::ip(IpAddr::V6(Ipv6Addr::new(
+        8193, 18528, 18434, 56, 0, 0, 0, 10,
+    ))),
+];
+
 /// Combined Cloudflare DNS IPv4 and IPv6 options
 #[cfg(feature = "cloudflare")]
 #[cfg_attr(docsrs, doc(cfg(feature = "cloudflare")))]
@@ -124,12 +208,8 @@ pub const CLOUDFLARE: &dyn crate::Resolver<'static> = &&[CLOUDFLARE_V4, CLOUDFLA
 #[cfg(feature = "cloudflare")]
 #[cfg_attr(docsrs, doc(cfg(feature = "cloudflare")))]
 pub const CLOUDFLARE_V4: &dyn crate::Resolver<'static> = &Resolver::new_static(
-    "whoami.cloudflare",
-    &[
-        IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)),
-        IpAddr::V4(Ipv4Addr::new(1, 0, 0, 1)),
-    ],
-    DEFAULT_DNS_PORT,
+    CLOUDFLARE_WHOAMI_NAME,
+    CLOUDFLARE_V4_SERVERS,
     QueryMethod::TXT,
     DNSClass::CH,
 );
@@ -138,23 +218,179 @@ pub const CLOUDFLARE_V4: &dyn crate::Resolver<'static> = &Resolver::new_static(
 #[cfg(feature = "cloudflare")]
 #[cfg_attr(docsrs, doc(cfg(feature = "cloudflare")))]
 pub const CLOUDFLARE_V6: &dyn crate::Resolver<'static> = &Resolver::new_static(
-    "whoami.cloudflare",
-    &[
-        // 2606:4700:4700::1111
-        IpAddr::V6(Ipv6Addr::new(9734, 18176, 18176, 0, 0, 0, 0, 4369)),
-        // 2606:4700:4700::1001
-        IpAddr::V6(Ipv6Addr::new(9734, 18176, 18176, 0, 0, 0, 0, 4097)),
-    ],
-    DEFAULT_DNS_PORT,
+    CLOUDFLARE_WHOAMI_NAME,
+    CLOUDFLARE_V6_SERVERS,
     QueryMethod::TXT,
     DNSClass::CH,
 );
 
+/// Cloudflare's public recursive resolvers.
+#[cfg(feature = "cloudflare")]
+const CLOUDFLARE_V4_SERVERS: &[Server<'static>] = &[
+    Server::ip(IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1))),
+    Server::ip(IpAddr::V4(Ipv4Addr::new(1, 0, 0, 1))),
+];
+
+#[cfg(feature = "cloudflare")]
+const CLOUDFLARE_V6_SERVERS: &[Server<'static>] = &[
+    // 2606:4700:4700::1111
+    Server::ip(IpAddr::V6(Ipv6Addr::new(
+        9734, 18176, 18176, 0, 0, 0, 0, 4369,
+    ))),
+    // 2606:4700:4700::1001
+    Server::ip(IpAddr::V6(Ipv6Addr::new(
+        9734, 18176, 18176, 0, 0, 0, 0, 4097,
+    ))),
+];
+
+/// Combined Akamai IPv4 and IPv6 options.
+#[cfg(feature = "akamai")]
+#[cfg_attr(docsrs, doc(cfg(feature = "akamai")))]
+pub const AKAMAI: &dyn crate::Resolver<'static> = &&[AKAMAI_V4, AKAMAI_V6];
+
+/// Akamai IPv4 DNS resolver options.
+#[cfg(feature = "akamai")]
+#[cfg_attr(docsrs, doc(cfg(feature = "akamai")))]
+pub const AKAMAI_V4: &dyn crate::Resolver<'static> = &Resolver::new_static(
+    AKAMAI_WHOAMI_NAME,
+    AKAMAI_SERVERS,
+    QueryMethod::A,
+    DNSClass::IN,
+);
+
+/// Akamai IPv6 DNS resolver options.
+#[cfg(feature = "akamai")]
+#[cfg_attr(docsrs, doc(cfg(feature = "akamai")))]
+pub const AKAMAI_V6: &dyn crate::Resolver<'static> = &Resolver::new_static(
+    AKAMAI_WHOAMI_NAME,
+    AKAMAI_SERVERS,
+    QueryMethod::AAAA,
+    DNSClass::IN,
+);
+
+/// Akamai's authoritative name server for `akamai.net`, resolved to an
+/// address of the IP version of the record queried (see
+/// [`QueryMethod::server_version`]).
+#[cfg(feature = "akamai")]
+const AKAMAI_SERVERS: &[Server<'static>] =
+    &[Server::Host("ns1-1.akamaitech.net", DEFAULT_DNS_PORT)];
+
+/// All builtin resolvers of the egress address of the system's recursive
+/// resolvers.
+///
+/// These query whoami services through the recursive resolvers configured on
+/// the system (see [`Server::System`]), so the addresses they resolve are the
+/// addresses those resolvers send queries from, not our own. Comparing them
+/// with our public IP address can reveal DNS leaks, for example when a VPN is
+/// up. They are not included in [`ALL`].
+pub const RESOLVER_EGRESS: &dyn crate::Resolver<'static> = &&[
+    #[cfg(feature = "google")]
+    RESOLVER_EGRESS_GOOGLE,
+    #[cfg(feature = "akamai")]
+    RESOLVER_EGRESS_AKAMAI,
+];
+
+/// Google egress resolver of the system's recursive resolvers.
+///
+/// See [`Details::google_whoami`] for the client subnet forwarded by the
+/// recursive resolver.
+#[cfg(feature = "google")]
+#[cfg_attr(docsrs, doc(cfg(feature = "google")))]
+pub const RESOLVER_EGRESS_GOOGLE: &dyn crate::Resolver<'static> = &Resolver::new_static(
+    GOOGLE_WHOAMI_NAME,
+    &[Server::System],
+    QueryMethod::TXT,
+    DNSClass::IN,
+);
+
+/// Akamai egress resolver of the system's recursive resolvers.
+///
+/// Akamai answers with the address the query reached its servers from as an
+/// `A` record, so only IPv4 egress addresses are resolved.
+#[cfg(feature = "akamai")]
+#[cfg_attr(docsrs, doc(cfg(feature = "akamai")))]
+pub const RESOLVER_EGRESS_AKAMAI: &dyn crate::Resolver<'static> = &Resolver::new_static(
+    AKAMAI_WHOAMI_NAME,
+    &[Server::System],
+    QueryMethod::A,
+    DNSClass::IN,
+);
+
 ///////////////////////////////////////////////////////////////////////////////
 // Error
 
 /// DNS resolver error.
-pub type Error = ProtoError;
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// The query timed out.
+    #[error("query timed out")]
+    Timeout,
+    /// The server refused the connection.
+    #[error("connection refused")]
+    ConnectionRefused,
+    /// The queried name does not exist (`NXDOMAIN`).
+    ///
+    /// The details of the response are available from [`Error::details`].
+    #[error("name does not exist")]
+    NxDomain(Box<Details>),
+    /// The server failed to answer the query (`SERVFAIL`).
+    ///
+    /// The details of the response are available from [`Error::details`].
+    #[error("server failure")]
+    ServFail(Box<Details>),
+    /// The server answered with another error response code.
+    ///
+    /// The details of the response are available from [`Error::details`].
+    #[error("server returned response code {0}")]
+    Rcode(u16, Box<Details>),
+    /// A name could not be parsed.
+    #[error("invalid name")]
+    Parse(#[source] Box<dyn StdError + Send + Sync + 'static>),
+    /// The response did not match the query that was sent.
+    #[error("invalid response: {0}")]
+    InvalidResponse(&'static str),
+    /// A server hostname did not resolve to an address of the requested IP
+    /// version.
+    #[error("no address found for server {0}")]
+    ServerLookup(String),
+    /// Other DNS client error.
+    #[error("dns client error")]
+    Other(#[source] Box<dyn StdError + Send + Sync + 'static>),
+}
+
+impl Error {
+    fn proto(error: ProtoError) -> Self {
+        match error.kind() {
+            ProtoErrorKind::Timeout => Self::Timeout,
+            ProtoErrorKind::Io(io) if io.kind() == io::ErrorKind::ConnectionRefused => {
+                Self::ConnectionRefused
+            }
+            _ => Self::Other(error.into()),
+        }
+    }
+
+    /// The details of the response, for errors returned by the server.
+    #[must_use]
+    pub fn details(&self) -> Option<&Details> {
+        match self {
+            Self::NxDomain(details) | Self::ServFail(details) | Self::Rcode(_, details) => {
+                Some(details)
+            }
+            _ => None,
+        }
+    }
+
+    /// Fails with the details of responses with an error response code.
+    fn from_response(details: Box<Details>) -> Result<Box<Details>, Self> {
+        match details.rcode {
+            ResponseCode::NoError => Ok(details),
+            ResponseCode::NXDomain => Err(Self::NxDomain(details)),
+            ResponseCode::ServFail => Err(Self::ServFail(details)),
+            rcode => Err(Self::Rcode(rcode.into(), details)),
+        }
+    }
+}
 
 ///////////////////////////////////////////////////////////////////////////////
 // Details & options
@@ -164,7 +400,16 @@ pub type Error = ProtoError;
 pub struct Details {
     name: Name,
     server: SocketAddr,
+    server_host: Option<String>,
     method: QueryMethod,
+    class: DNSClass,
+    transport: Transport,
+    protections: Protections,
+    response_time: Duration,
+    rcode: ResponseCode,
+    answers: Vec<Record>,
+    edns: Option<Edns>,
+    server_identity: Option<String>,
 }
 
 impl Details {
@@ -175,20 +420,243 @@ impl Details {
     }
 
     /// DNS server used in the resolution of our IP address.
+    ///
+    /// If the server was specified by hostname, this is the address it was
+    /// resolved to.
     #[must_use]
     pub fn server(&self) -> SocketAddr {
         self.server
     }
 
+    /// Hostname of the DNS server used, if it was specified by hostname.
+    #[must_use]
+    pub fn server_host(&self) -> Option<&str> {
+        self.server_host.as_deref()
+    }
+
     /// The query method used in the resolution of our IP address.
     #[must_use]
-    pub fn query_method(&self) -> QueryMethod {
-        self.method
+    pub fn query_method(&self) -> &QueryMethod {
+        &self.method
+    }
+
+    /// DNS class used in the resolution of our IP address.
+    #[must_use]
+    pub fn class(&self) -> DNSClass {
+        self.class
+    }
+
+    /// Transport used in the resolution of our IP address.
+    #[must_use]
+    pub fn transport(&self) -> Transport {
+        self.transport
+    }
+
+    /// The anti-spoofing protections in effect for the resolution of our IP
+    /// address.
+    #[must_use]
+    pub fn protections(&self) -> Protections {
+        self.protections
+    }
+
+    /// Time taken between sending the query that was answered and receiving
+    /// its response.
+    #[must_use]
+    pub fn response_time(&self) -> Duration {
+        self.response_time
+    }
+
+    /// Response code returned by the DNS server.
+    ///
+    /// Resolutions only succeed with `NOERROR`. Other response codes fail with
+    /// an [`Error`] holding the details of the response.
+    #[must_use]
+    pub fn rcode(&self) -> ResponseCode {
+        self.rcode
+    }
+
+    /// TTL of the first answer record, from which our IP address was
+    /// extracted.
+    #[must_use]
+    pub fn ttl(&self) -> Option<u32> {
+        self.answers.first().map(Record::ttl)
+    }
+
+    /// All answer records returned by the DNS server.
+    #[must_use]
+    pub fn answers(&self) -> &[Record] {
+        &self.answers
+    }
+
+    /// EDNS section returned by the DNS server, if any.
+    #[must_use]
+    pub fn edns(&self) -> Option<&Edns> {
+        self.edns.as_ref()
+    }
+
+    /// EDNS client subnet ([RFC 7871]) returned by the DNS server, if any.
+    ///
+    /// [RFC 7871]: https://tools.ietf.org/html/rfc7871
+    #[must_use]
+    pub fn client_subnet(&self) -> Option<ClientSubnet> {
+        let option = self.edns.as_ref()?.option(EdnsCode::Subnet)?;
+        ClientSubnet::from_option(option)
+    }
+
+    /// Facts returned by Google's `o-o.myaddr.l.google.com` whoami service,
+    /// if it was used in the resolution of our IP address.
+    #[must_use]
+    pub fn google_whoami(&self) -> Option<GoogleWhoami> {
+        if self.name != Name::from_ascii(GOOGLE_WHOAMI_NAME).ok()? {
+            return None;
+        }
+        GoogleWhoami::from_answers(&self.answers)
+    }
+
+    /// Identity of the server instance that answered, such as an anycast
+    /// site, if requested with [`Resolver::with_identity_method`] and
+    /// returned by the server.
+    #[must_use]
+    pub fn server_identity(&self) -> Option<&str> {
+        self.server_identity.as_deref()
+    }
+}
+
+/// Facts returned by Google's `o-o.myaddr.l.google.com` whoami service.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct GoogleWhoami {
+    requester: IpAddr,
+    client_subnet: Option<(IpAddr, u8)>,
+}
+
+impl GoogleWhoami {
+    /// The address that sent the query to Google's servers.
+    ///
+    /// When Google's servers are queried directly this is our IP address.
+    /// When queried through a recursive resolver, it is the egress address of
+    /// that resolver.
+    #[must_use]
+    pub fn requester(&self) -> IpAddr {
+        self.requester
+    }
+
+    /// The client subnet a recursive resolver forwarded on our behalf, as an
+    /// address and prefix length.
+    #[must_use]
+    pub fn client_subnet(&self) -> Option<(IpAddr, u8)> {
+        self.client_subnet
+    }
+
+    fn from_answers(answers: &[Record]) -> Option<Self> {
+        let mut requester = None;
+        let mut client_subnet = None;
+        for txt in txt_strings(answers) {
+            if let Some(subnet) = txt.strip_prefix("edns0-client-subnet ") {
+                client_subnet = subnet
+                    .split_once('/')
+                    .and_then(|(addr, prefix)| Some((addr.parse().ok()?, prefix.parse().ok()?)));
+            } else if let Ok(addr) = txt.parse() {
+                requester.get_or_insert(addr);
+            }
+        }
+        Some(Self {
+            requester: requester?,
+            client_subnet,
+        })
+    }
+}
+
+/// EDNS client subnet returned by a DNS server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ClientSubnet {
+    addr: IpAddr,
+    source_prefix: u8,
+    scope_prefix: u8,
+}
+
+impl ClientSubnet {
+    /// The address of the client subnet.
+    #[must_use]
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    /// The prefix length of the client subnet sent in the query.
+    #[must_use]
+    pub fn source_prefix(&self) -> u8 {
+        self.source_prefix
+    }
+
+    /// The prefix length the answer applies to, as chosen by the server.
+    #[must_use]
+    pub fn scope_prefix(&self) -> u8 {
+        self.scope_prefix
+    }
+
+    fn from_option(option: &EdnsOption) -> Option<Self> {
+        let data = Vec::<u8>::try_from(option).ok()?;
+        let [family_high, family_low, source_prefix, scope_prefix, addr @ ..] = data.as_slice()
+        else {
+            return None;
+        };
+        let addr = match u16::from_be_bytes([*family_high, *family_low]) {
+            1 => {
+                let mut octets = [0; 4];
+                octets.get_mut(..addr.len())?.copy_from_slice(addr);
+                IpAddr::V4(octets.into())
+            }
+            2 => {
+                let mut octets = [0; 16];
+                octets.get_mut(..addr.len())?.copy_from_slice(addr);
+                IpAddr::V6(octets.into())
+            }
+            _ => return None,
+        };
+        Some(Self {
+            addr,
+            source_prefix: *source_prefix,
+            scope_prefix: *scope_prefix,
+        })
+    }
+}
+
+/// Anti-spoofing protections in effect for a DNS resolution.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Protections {
+    case_randomization: bool,
+    cookie: bool,
+    source_matching: bool,
+}
+
+impl Protections {
+    /// Whether the query name was sent with randomized case (0x20 encoding)
+    /// and echoed back exactly by the server.
+    #[must_use]
+    pub fn case_randomization(&self) -> bool {
+        self.case_randomization
+    }
+
+    /// Whether a DNS cookie ([RFC 7873]) was sent and echoed back by the
+    /// server.
+    ///
+    /// [RFC 7873]: https://tools.ietf.org/html/rfc7873
+    #[must_use]
+    pub fn cookie(&self) -> bool {
+        self.cookie
+    }
+
+    /// Whether the response was only accepted from the address and port the
+    /// query was sent to.
+    ///
+    /// This is always enforced for both UDP and TCP.
+    #[must_use]
+    pub fn source_matching(&self) -> bool {
+        self.source_matching
     }
 }
 
 /// Method used to query an IP address from a DNS server
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 #[allow(clippy::upper_case_acronyms)]
 pub enum QueryMethod {
     /// The first queried `A` name record is extracted as our IP address.
@@ -197,6 +665,191 @@ pub enum QueryMethod {
     AAAA,
     /// The first `TXT` record is extracted and parsed as our IP address.
     TXT,
+    /// Records of the given type are queried and our IP address is extracted
+    /// from the answers with the [`Extractor`].
+    Custom(RecordType, Extractor),
+}
+
+impl QueryMethod {
+    fn record_type(&self) -> RecordType {
+        match self {
+            QueryMethod::A => RecordType::A,
+            QueryMethod::AAAA => RecordType::AAAA,
+            QueryMethod::TXT => RecordType::TXT,
+            QueryMethod::Custom(record_type, _) => *record_type,
+        }
+    }
+
+    /// The IP version a [`Server::Host`] is looked up and queried over.
+    ///
+    /// Whoami answers describe the address a query is sent from, so address
+    /// queries are sent over the version of the address they ask for.
+    fn server_version(&self, version: Version) -> Version {
+        match (version, self) {
+            (Version::Any, QueryMethod::A) => Version::V4,
+            (Version::Any, QueryMethod::AAAA) => Version::V6,
+            _ => version,
+        }
+    }
+}
+
+/// The function of an [`Extractor`].
+pub type ExtractFn = dyn Fn(&[Record]) -> Result<IpAddr, crate::Error> + Send + Sync;
+
+/// A user-supplied function extracting our IP address from the answer
+/// records returned by a DNS server.
+///
+/// ```
+/// use std::net::IpAddr;
+///
+/// use hickory_proto::rr::{RData, RecordType};
+/// use public_ip::dns::{Extractor, QueryMethod};
+///
+/// // Extracts the address from a `TXT` answer of the form `ip=1.2.3.4`.
+/// const METHOD: QueryMethod = QueryMethod::Custom(
+///     RecordType::TXT,
+///     Extractor::new_static(&|answers| {
+///         answers
+///             .iter()
+///             .filter_map(|answer| match answer.data() {
+///                 Some(RData::TXT(txt)) => Some(txt.to_string()),
+///                 _ => None,
+///             })
+///             .find_map(|txt| txt.strip_prefix("ip=")?.parse::<IpAddr>().ok())
+///             .ok_or(public_ip::Error::Addr)
+///     }),
+/// );
+/// ```
+pub type Extractor = crate::Extractor<ExtractFn>;
+
+impl Extractor {
+    /// Create a new extractor from a function.
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(&[Record]) -> Result<IpAddr, crate::Error> + Send + Sync + 'static,
+    {
+        Self::shared(Arc::new(f))
+    }
+
+    fn extract(&self, answers: &[Record]) -> Result<IpAddr, crate::Error> {
+        self.get()(answers)
+    }
+}
+
+/// A DNS server to query.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Server<'r> {
+    /// A DNS server at a socket address.
+    Addr(SocketAddr),
+    /// A DNS server hostname and port, resolved to an address when queried.
+    ///
+    /// See [`Bootstrap`] for how the hostname is resolved. With
+    /// [`QueryMethod::A`] or [`QueryMethod::AAAA`], the hostname is resolved
+    /// to an address of the same IP version as the record queried.
+    Host(&'r str, u16),
+    /// The recursive resolvers configured on the system, read from
+    /// `/etc/resolv.conf` when queried.
+    ///
+    /// Unlike other servers, these are queried regardless of the IP version
+    /// requested, as the address a recursive resolver answers for is not tied
+    /// to the version of the address it is reached at.
+    ///
+    /// A missing `/etc/resolv.conf` means no servers are queried. Scoped IPv6
+    /// addresses such as `fe80::1%eth0` are queried on the interface given by
+    /// name or index; addresses scoped to an unknown interface are skipped.
+    System,
+}
+
+impl Server<'_> {
+    /// A DNS server at an IP address on the default DNS port (53).
+    #[must_use]
+    pub const fn ip(addr: IpAddr) -> Self {
+        Self::Addr(SocketAddr::new(addr, DEFAULT_DNS_PORT))
+    }
+}
+
+/// Method used to resolve the address of a [`Server::Host`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Bootstrap {
+    /// Resolve the hostname with the system resolver.
+    System,
+    /// Resolve the hostname by querying the DNS server at the given address.
+    Server(SocketAddr),
+}
+
+/// Transport used to send DNS queries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Transport {
+    /// DNS over UDP.
+    Udp,
+    /// DNS over TCP.
+    Tcp,
+}
+
+/// Method used to identify the server instance answering a query.
+///
+/// Anycast DNS services answer from many sites sharing the same address, and
+/// these methods ask the server which one it is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum IdentityMethod {
+    /// The NSID EDNS option ([RFC 5001]) is requested with the query.
+    ///
+    /// [RFC 5001]: https://tools.ietf.org/html/rfc5001
+    Nsid,
+    /// A separate `id.server` `CH` `TXT` query ([RFC 4892]) is sent to the
+    /// same server.
+    ///
+    /// [RFC 4892]: https://tools.ietf.org/html/rfc4892
+    IdServer,
+    /// A separate `hostname.bind` `CH` `TXT` query is sent to the same
+    /// server.
+    HostnameBind,
+}
+
+/// Options for the DNS messages sent by a [`Resolver`].
+#[derive(Debug, Clone, Copy)]
+struct MessageOptions {
+    recursion_desired: bool,
+    edns: bool,
+    edns_payload: u16,
+    dnssec_ok: bool,
+}
+
+/// Options applied to every query sent by a [`Resolver`].
+#[derive(Debug, Clone, Copy)]
+struct QueryOptions {
+    message: MessageOptions,
+    bootstrap: Bootstrap,
+    transport: Transport,
+    timeout: Duration,
+    retransmissions: u8,
+    server_delay: Duration,
+    case_randomization: bool,
+    cookies: bool,
+    identity: Option<IdentityMethod>,
+}
+
+impl QueryOptions {
+    const fn new() -> Self {
+        Self {
+            message: MessageOptions {
+                recursion_desired: true,
+                edns: true,
+                edns_payload: MAX_PAYLOAD_LEN,
+                dnssec_ok: false,
+            },
+            bootstrap: Bootstrap::System,
+            transport: Transport::Udp,
+            timeout: DEFAULT_TIMEOUT,
+            retransmissions: 0,
+            server_delay: Duration::ZERO,
+            case_randomization: false,
+            cookies: false,
+            identity: None,
+        }
+    }
 }
 
 ///////////////////////////////////////////////////////////////////////////////
@@ -205,26 +858,160 @@ pub enum QueryMethod {
 /// Options to build a DNS resolver.
 #[derive(Debug)]
 pub struct Resolver<'r> {
-    port: u16,
     name: Cow<'r, str>,
-    servers: Cow<'r, [IpAddr]>,
+    servers: Cow<'r, [Server<'r>]>,
     method: QueryMethod,
     class: DNSClass,
+    options: QueryOptions,
+    pool: Option<Pool>,
 }
 
 impl<'r> Resolver<'r> {
     /// Create a new DNS resolver.
-    pub fn new<N, S>(name: N, servers: S, port: u16, method: QueryMethod, class: DNSClass) -> Self
+    pub fn new<N, S>(name: N, servers: S, method: QueryMethod, class: DNSClass) -> Self
     where
         N: Into<Cow<'r, str>>,
-        S: Into<Cow<'r, [IpAddr]>>,
+        S: Into<Cow<'r, [Server<'r>]>>,
     {
         Self {
-            port,
             name: name.into(),
             servers: servers.into(),
             method,
             class,
+            options: QueryOptions::new(),
+            pool: None,
+        }
+    }
+
+    /// Set how [`Server::Host`] entries are resolved (defaults to
+    /// [`Bootstrap::System`]).
+    #[must_use]
+    pub const fn with_bootstrap(mut self, bootstrap: Bootstrap) -> Self {
+        self.options.bootstrap = bootstrap;
+        self
+    }
+
+    /// Set the transport used to send queries (defaults to [`Transport::Udp`]).
+    #[must_use]
+    pub const fn with_transport(mut self, transport: Transport) -> Self {
+        self.options.transport = transport;
+        self
+    }
+
+    /// Set how long to wait for a response to each query sent (defaults to
+    /// 5 seconds).
+    #[must_use]
+    pub const fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.options.timeout = timeout;
+        self
+    }
+
+    /// Set how many times a query that timed out is resent to the same server
+    /// over UDP before moving on to the next one (defaults to 0).
+    #[must_use]
+    pub const fn with_retransmissions(mut self, retransmissions: u8) -> Self {
+        self.options.retransmissions = retransmissions;
+        self
+    }
+
+    /// Set the delay before querying each server after the first (defaults
+    /// to no delay).
+    #[must_use]
+    pub const fn with_server_delay(mut self, delay: Duration) -> Self {
+        self.options.server_delay = delay;
+        self
+    }
+
+    /// Set whether the case of the query name is randomized (0x20 encoding)
+    /// and verified in responses (defaults to `false`).
+    ///
+    /// Responses that do not echo the query name with the exact same case are
+    /// rejected, so this should only be enabled for servers known to preserve
+    /// it.
+    #[must_use]
+    pub const fn with_case_randomization(mut self, enabled: bool) -> Self {
+        self.options.case_randomization = enabled;
+        self
+    }
+
+    /// Set whether a DNS cookie ([RFC 7873]) is sent and verified in
+    /// responses (defaults to `false`).
+    ///
+    /// Responses carrying a cookie that does not match the one sent are
+    /// rejected. With a [`Pool`], the server cookie returned by each server is
+    /// sent back with later queries, and responses without a cookie are
+    /// rejected from servers that returned one before. Without a pool, every
+    /// query starts a new exchange, so responses without a cookie are accepted
+    /// as servers may not support cookies.
+    ///
+    /// With cookies enabled, a `FORMERR` response is not retried without
+    /// EDNS, as that would drop the cookie.
+    ///
+    /// [RFC 7873]: https://tools.ietf.org/html/rfc7873
+    #[must_use]
+    pub const fn with_cookies(mut self, enabled: bool) -> Self {
+        self.options.cookies = enabled;
+        self
+    }
+
+    /// Set whether queries ask the server to answer recursively (defaults to
+    /// `true`).
+    ///
+    /// Some authoritative servers refuse or answer differently to queries
+    /// with the recursion desired (RD) bit set.
+    #[must_use]
+    pub const fn with_recursion_desired(mut self, enabled: bool) -> Self {
+        self.options.message.recursion_desired = enabled;
+        self
+    }
+
+    /// Set whether queries are sent with EDNS (defaults to `true`).
+    ///
+    /// If a server responds with `FORMERR` to a query sent with EDNS, the
+    /// query is retried once without it, unless [cookies] are enabled. DNS
+    /// cookies require EDNS and are not sent when it is disabled.
+    ///
+    /// [cookies]: Resolver::with_cookies
+    #[must_use]
+    pub const fn with_edns(mut self, enabled: bool) -> Self {
+        self.options.message.edns = enabled;
+        self
+    }
+
+    /// Set the UDP payload size advertised with EDNS (defaults to 1232).
+    #[must_use]
+    pub const fn with_edns_payload(mut self, payload: u16) -> Self {
+        self.options.message.edns_payload = payload;
+        self
+    }
+
+    /// Set whether the DNSSEC OK (DO) bit is set with EDNS (defaults to
+    /// `false`).
+    #[must_use]
+    pub const fn with_dnssec_ok(mut self, enabled: bool) -> Self {
+        self.options.message.dnssec_ok = enabled;
+        self
+    }
+
+    /// Set the method used to identify the server instance that answered
+    /// (defaults to none).
+    ///
+    /// The identity is available through [`Details::server_identity`]. Failing
+    /// to identify the server does not fail the resolution.
+    #[must_use]
+    pub const fn with_identity_method(mut self, method: IdentityMethod) -> Self {
+        self.options.identity = Some(method);
+        self
+    }
+
+    /// Set a [`Pool`] of clients to reuse across resolutions.
+    ///
+    /// Without a pool, a new client is created for every query.
+    #[must_use]
+    pub fn with_pool(self, pool: Pool) -> Self {
+        Self {
+            pool: Some(pool),
+            ..self
         }
     }
 }
@@ -234,70 +1021,119 @@ impl Resolver<'static> {
     #[must_use]
     pub const fn new_static(
         name: &'static str,
-        servers: &'static [IpAddr],
-        port: u16,
+        servers: &'static [Server<'static>],
         method: QueryMethod,
         class: DNSClass,
     ) -> Self {
         Self {
-            port,
             name: Cow::Borrowed(name),
             servers: Cow::Borrowed(servers),
             method,
             class,
+            options: QueryOptions::new(),
+            pool: None,
         }
     }
 }
 
 impl<'r> crate::Resolver<'r> for Resolver<'r> {
     fn resolve(&self, version: Version) -> Resolutions<'r> {
-        let port = self.port;
-        let method = self.method;
+        let method = self.method.clone();
+        let options = self.options;
         let name = match Name::from_ascii(self.name.as_ref()) {
             Ok(name) => name,
-            Err(err) => return Box::pin(stream::once(future::ready(Err(crate::Error::new(err))))),
+            Err(err) => {
+                let err = Error::Parse(err.into());
+                return Box::pin(stream::once(future::ready(Err(err.into()))));
+            }
         };
-        let mut servers: Vec<_> = self
+        // Servers specified by hostname can only be filtered by version once
+        // they are resolved.
+        let servers: Vec<_> = self
             .servers
             .iter()
             .copied()
-            .filter(|addr| version.matches(*addr))
+            .filter(|server| !matches!(server, Server::Addr(addr) if !version.matches(addr.ip())))
             .collect();
-        let first_server = match servers.pop() {
-            Some(server) => server,
-            None => return Box::pin(stream::empty()),
-        };
-        let record_type = match self.method {
-            QueryMethod::A => RecordType::A,
-            QueryMethod::AAAA => RecordType::AAAA,
-            QueryMethod::TXT => RecordType::TXT,
-        };
-        let span = trace_span!("dns resolver", ?version, ?method, %name, %port);
-        let mut query = Query::query(name, record_type);
+        let span = trace_span!("dns resolver", ?version, ?method, %name);
+        let mut query = Query::query(name, method.record_type());
         query.set_query_class(self.class);
-        let stream = resolve(first_server, port, query.clone(), method);
-        let resolutions = DnsResolutions {
-            port,
-            version,
-            query,
-            method,
-            servers,
-            stream,
+        let pool = self.pool.clone();
+        // Reading the system configuration blocks, so it is done once the
+        // stream is polled.
+        let resolutions = if servers.contains(&Server::System) {
+            let resolutions = async move {
+                let servers = expand_system_servers(servers).await?;
+                Ok::<_, crate::Error>(dns_resolutions(
+                    version, options, pool, query, method, servers,
+                ))
+            };
+            Box::pin(stream::once(resolutions).try_flatten())
+        } else {
+            dns_resolutions(version, options, pool, query, method, servers)
         };
         Box::pin(resolutions.instrument(span))
     }
 }
 
+fn dns_resolutions(
+    version: Version,
+    options: QueryOptions,
+    pool: Option<Pool>,
+    query: Query,
+    method: QueryMethod,
+    mut servers: Vec<Server<'_>>,
+) -> Resolutions<'_> {
+    let Some(first_server) = servers.pop() else {
+        return Box::pin(stream::empty());
+    };
+    let stream = resolve(
+        first_server,
+        version,
+        query.clone(),
+        method.clone(),
+        options,
+        pool.clone(),
+        Duration::ZERO,
+    );
+    Box::pin(DnsResolutions {
+        version,
+        options,
+        pool,
+        query,
+        method,
+        servers,
+        stream,
+    })
+}
+
+/// Replaces [`Server::System`] with the servers configured on the system.
+async fn expand_system_servers(servers: Vec<Server<'_>>) -> Result<Vec<Server<'_>>, Error> {
+    let system = tokio::task::spawn_blocking(system_servers)
+        .await
+        .map_err(|err| Error::Other(err.into()))?
+        .map_err(|err| Error::Other(err.into()))?;
+    let mut expanded = Vec::with_capacity(servers.len() + system.len());
+    for server in servers {
+        match server {
+            Server::System => expanded.extend(system.iter().copied().map(Server::Addr)),
+            server => expanded.push(server),
+        }
+    }
+    Ok(expanded)
+}
+
 ///////////////////////////////////////////////////////////////////////////////
 // Resolutions
 
 pin_project! {
     struct DnsResolutions<'r> {
-        port: u16,
         version: Version,
+        options: QueryOptions,
+        pool: Option<Pool>,
         query: Query,
         method: QueryMethod,
-        servers: Vec<IpAddr>,
+        servers: Vec<Server<'r>>,
         #[pin]
         stream: Resolutions<'r>,
     }
@@ -310,7 +1146,15 @@ impl<'r> Stream for DnsResolutions<'r> {
         match ready!(self.as_mut().project().stream.poll_next(cx)) {
             Some(o) => Poll::Ready(Some(o)),
             None => self.servers.pop().map_or(Poll::Ready(None), |server| {
-                self.stream = resolve(server, self.port, self.query.clone(), self.method);
+                self.stream = resolve(
+                    server,
+                    self.version,
+                    self.query.clone(),
+                    self.method.clone(),
+                    self.options,
+                    self.pool.clone(),
+                    self.options.server_delay,
+                );
                 self.project().stream.poll_next(cx)
             }),
         }
@@ -320,55 +1164,1288 @@ impl<'r> Stream for DnsResolutions<'r> {
 ///////////////////////////////////////////////////////////////////////////////
 // Client
 
+/// A response to a query along with how it was received.
+struct Exchange {
+    response: DnsResponse,
+    protections: Protections,
+    response_time: Duration,
+}
+
 async fn dns_query(
     server: SocketAddr,
     query: Query,
-    query_opts: DnsRequestOptions,
-) -> Result<DnsResponse, ProtoError> {
+    options: QueryOptions,
+    pool: Option<&Pool>,
+) -> Result<Exchange, Error> {
+    let client = match pool {
+        Some(pool) => pool.client(server, options).await?,
+        None => connect(server, options).await?,
+    };
+    let result = send_query(&client, server, query, options, pool).await;
+    // A failed client may have lost its connection, so it is not reused.
+    if let (Some(pool), Err(_)) = (pool, &result) {
+        pool.remove(server, options);
+    }
+    result
+}
+
+async fn send_query(
+    client: &AsyncClient,
+    server: SocketAddr,
+    mut query: Query,
+    options: QueryOptions,
+    pool: Option<&Pool>,
+) -> Result<Exchange, Error> {
+    if options.case_randomization {
+        let name = randomize_case(query.name())?;
+        query.set_name(name);
+    }
+    let mut message_opts = options.message;
+    let nsid = options.identity == Some(IdentityMethod::Nsid);
+    let mut cookie = (options.cookies && message_opts.edns)
+        .then(|| pool.map_or_else(Cookie::new, |pool| pool.cookie(server)));
+    let mut bad_cookie = false;
+    let mut retransmissions = match options.transport {
+        Transport::Udp => options.retransmissions,
+        Transport::Tcp => 0,
+    };
+    loop {
+        let request = DnsRequest::new(
+            build_message(query.clone(), message_opts, cookie.as_ref(), nsid),
+            DnsRequestOptions::default(),
+        );
+        let sent = Instant::now();
+        let response = tokio::time::timeout(options.timeout, client.send(request).next())
+            .await
+            .unwrap_or_else(|_| Some(Err(ProtoErrorKind::Timeout.into())))
+            .map(|response| response.map_err(Error::proto));
+        match response {
+            Some(Err(Error::Timeout)) if retransmissions > 0 => {
+                trace!(%server, "query timed out, retransmitting");
+                retransmissions -= 1;
+            }
+            response => {
+                let response = response.ok_or(Error::InvalidResponse("expected a response"))??;
+                let response_time = sent.elapsed();
+                let protections = verify_response(&response, &query, cookie.as_mut(), options)?;
+                // Some servers and middleboxes reject EDNS outright. Falling
+                // back would also drop cookies, so a forged FORMERR could be
+                // used to disable them; the fallback is skipped when cookies
+                // were requested.
+                if response.response_code() == ResponseCode::FormErr
+                    && message_opts.edns
+                    && cookie.is_none()
+                {
+                    trace!(%server, "query returned FORMERR, retrying without EDNS");
+                    message_opts.edns = false;
+                    continue;
+                }
+                if let (Some(pool), Some(cookie)) = (pool, &cookie) {
+                    pool.set_cookie(server, cookie.clone());
+                }
+                // A server that does not accept our server cookie returns a
+                // new one to retry with.
+                if response.response_code() == ResponseCode::BADCOOKIE && !bad_cookie {
+                    trace!(%server, "query returned BADCOOKIE, retrying with the new cookie");
+                    bad_cookie = true;
+                    continue;
+                }
+                return Ok(Exchange {
+                    response,
+                    protections,
+                    response_time,
+                });
+            }
+        }
+    }
+}
+
+fn build_message(
+    query: Query,
+    options: MessageOptions,
+    cookie: Option<&Cookie>,
+    nsid: bool,
+) -> Message {
+    let mut message = Message::new();
+    message
+        .add_query(query)
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query)
+        .set_recursion_desired(options.recursion_desired);
+    if !options.edns {
+        return message;
+    }
+    let edns = message.extensions_mut().get_or_insert_with(Edns::new);
+    edns.set_max_payload(options.edns_payload)
+        .set_version(0)
+        .set_dnssec_ok(options.dnssec_ok);
+    if let Some(cookie) = cookie {
+        let mut data = cookie.client.to_vec();
+        data.extend(cookie.server.iter().flatten());
+        edns.options_mut()
+            .insert(EdnsOption::Unknown(EdnsCode::Cookie.into(), data));
+    }
+    if nsid {
+        edns.options_mut()
+            .insert(EdnsOption::Unknown(EdnsCode::NSID.into(), Vec::new()));
+    }
+    message
+}
+
+fn randomize_case(name: &Name) -> Result<Name, Error> {
+    let labels = name.iter().map(|label| {
+        label
+            .iter()
+            .map(|byte| {
+                if rand::random() {
+                    byte.to_ascii_uppercase()
+                } else {
+                    byte.to_ascii_lowercase()
+                }
+            })
+            .collect::<Vec<u8>>()
+    });
+    let mut randomized = Name::from_labels(labels).map_err(|err| Error::Parse(err.into()))?;
+    randomized.set_fqdn(name.is_fqdn());
+    Ok(randomized)
+}
+
+/// Verifies the protections of a response, updating the server cookie if it
+/// returned one.
+fn verify_response(
+    response: &DnsResponse,
+    query: &Query,
+    cookie: Option<&mut Cookie>,
+    options: QueryOptions,
+) -> Result<Protections, Error> {
+    if options.case_randomization {
+        match response.queries().first() {
+            Some(echoed) if echoed.name().eq_case(query.name()) => {}
+            _ => {
+                return Err(Error::InvalidResponse(
+                    "response query name does not match the case sent",
+                ))
+            }
+        }
+    }
+    let cookie = match cookie {
+        Some(cookie) => match response
+            .extensions()
+            .as_ref()
+            .and_then(|edns| edns.option(EdnsCode::Cookie))
+        {
+            Some(EdnsOption::Unknown(_, echoed)) => match echoed.strip_prefix(&cookie.client[..]) {
+                Some([]) => true,
+                Some(server) if (8..=32).contains(&server.len()) => {
+                    cookie.server = Some(server.to_vec());
+                    true
+                }
+                _ => {
+                    return Err(Error::InvalidResponse(
+                        "response cookie does not match the cookie sent",
+                    ))
+                }
+            },
+            Some(_) => {
+                return Err(Error::InvalidResponse(
+                    "response cookie does not match the cookie sent",
+                ))
+            }
+            // Servers supporting cookies return them with every response, so
+            // one left out may have been forged.
+            None if cookie.server.is_some() => {
+                return Err(Error::InvalidResponse(
+                    "response has no cookie, but the server returned one before",
+                ))
+            }
+            None => false,
+        },
+        None => false,
+    };
+    Ok(Protections {
+        case_randomization: options.case_randomization,
+        cookie,
+        source_matching: true,
+    })
+}
+
+/// A DNS cookie ([RFC 7873]) exchanged with a server.
+///
+/// [RFC 7873]: https://tools.ietf.org/html/rfc7873
+#[derive(Debug, Clone)]
+struct Cookie {
+    client: [u8; 8],
+    /// The server cookie last returned by the server, if any.
+    server: Option<Vec<u8>>,
+}
+
+impl Cookie {
+    fn new() -> Self {
+        Self {
+            client: rand::random(),
+            server: None,
+        }
+    }
+}
+
+async fn connect(server: SocketAddr, options: QueryOptions) -> Result<AsyncClient, Error> {
     let handle = Handle::current();
-    let stream = UdpClientStream::<UdpSocket>::new(server);
-    let (client, bg) = AsyncClient::connect(stream).await?;
-    handle.spawn(bg);
-    client
-        .lookup(query, query_opts)
-        .next()
-        .await
-        .transpose()?
-        .ok_or_else(|| ProtoErrorKind::Message("expected a response").into())
+    let client = match options.transport {
+        Transport::Udp => {
+            let stream = UdpClientStream::<UdpSocket>::with_timeout(server, options.timeout);
+            let (client, bg) = AsyncClient::connect(stream).await.map_err(Error::proto)?;
+            handle.spawn(bg);
+            client
+        }
+        Transport::Tcp => {
+            let (stream, sender) = TcpClientStream::<AsyncIoTokioAsStd<TcpStream>>::with_timeout(
+                server,
+                options.timeout,
+            );
+            let (client, bg) = AsyncClient::with_timeout(stream, sender, options.timeout, None)
+                .await
+                .map_err(Error::proto)?;
+            handle.spawn(bg);
+            client
+        }
+    };
+    Ok(client)
+}
+
+async fn lookup_server(
+    host: &str,
+    port: u16,
+    version: Version,
+    options: QueryOptions,
+    pool: Option<&Pool>,
+) -> Result<SocketAddr, Error> {
+    let addrs: Vec<IpAddr> = match options.bootstrap {
+        Bootstrap::System => tokio::net::lookup_host((host, port))
+            .await
+            .map_err(|err| Error::Other(err.into()))?
+            .map(|addr| addr.ip())
+            .collect(),
+        Bootstrap::Server(resolver) => {
+            lookup_host_with(resolver, host, version, options, pool).await?
+        }
+    };
+    addrs
+        .into_iter()
+        .find(|addr| version.matches(*addr))
+        .map(|addr| SocketAddr::new(addr, port))
+        .ok_or_else(|| Error::ServerLookup(host.to_owned()))
+}
+
+async fn lookup_host_with(
+    resolver: SocketAddr,
+    host: &str,
+    version: Version,
+    options: QueryOptions,
+    pool: Option<&Pool>,
+) -> Result<Vec<IpAddr>, Error> {
+    let name = Name::from_ascii(host).map_err(|err| Error::Parse(err.into()))?;
+    // The bootstrap server is a recursive resolver, so the options of the
+    // whoami query, such as disabling recursion, do not apply to it.
+    let mut bootstrap_options = QueryOptions::new();
+    bootstrap_options.timeout = options.timeout;
+    bootstrap_options.transport = options.transport;
+    let record_types: &[RecordType] = match version {
+        Version::V4 => &[RecordType::A],
+        Version::V6 => &[RecordType::AAAA],
+        Version::Any => &[RecordType::A, RecordType::AAAA],
+    };
+    let mut addrs = Vec::new();
+    for record_type in record_types {
+        let query = Query::query(name.clone(), *record_type);
+        let exchange = dns_query(resolver, query, bootstrap_options, pool).await?;
+        let rcode = exchange.response.response_code();
+        if rcode != ResponseCode::NoError {
+            trace!(%resolver, host, %rcode, "server lookup failed");
+            return Err(Error::ServerLookup(host.to_owned()));
+        }
+        addrs.extend(
+            exchange
+                .response
+                .answers()
+                .iter()
+                .filter_map(|answer| match answer.data() {
+                    Some(RData::A(addr)) => Some(IpAddr::V4(addr.0)),
+                    Some(RData::AAAA(addr)) => Some(IpAddr::V6(addr.0)),
+                    _ => None,
+                }),
+        );
+    }
+    Ok(addrs)
+}
+
+async fn query_identity(
+    server: SocketAddr,
+    method: IdentityMethod,
+    options: QueryOptions,
+    pool: Option<&Pool>,
+) -> Option<String> {
+    let name = match method {
+        IdentityMethod::HostnameBind => "hostname.bind",
+        _ => "id.server",
+    };
+    let mut query = Query::query(Name::from_ascii(name).ok()?, RecordType::TXT);
+    query.set_query_class(DNSClass::CH);
+    match dns_query(server, query, options, pool).await {
+        Ok(exchange) => txt_strings(exchange.response.answers())
+            .next()
+            .map(str::to_owned),
+        Err(err) => {
+            trace!(%server, %err, "failed to identify server");
+            None
+        }
+    }
+}
+
+fn system_servers() -> io::Result<Vec<SocketAddr>> {
+    let resolv_conf = match fs::read_to_string("/etc/resolv.conf") {
+        Ok(resolv_conf) => resolv_conf,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            trace!("no /etc/resolv.conf, no system servers to query");
+            return Ok(Vec::new());
+        }
+        Err(err) => return Err(err),
+    };
+    Ok(parse_resolv_conf(&resolv_conf, |name| {
+        fs::read_to_string(format!("/sys/class/net/{name}/ifindex"))
+            .ok()?
+            .trim()
+            .parse()
+            .ok()
+    }))
+}
+
+fn parse_resolv_conf(
+    resolv_conf: &str,
+    interface_index: impl Fn(&str) -> Option<u32>,
+) -> Vec<SocketAddr> {
+    resolv_conf
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            (fields.next()? == "nameserver").then_some(fields.next()?)
+        })
+        .filter_map(|addr| {
+            let Some((addr, scope)) = addr.split_once('%') else {
+                let addr = addr.parse().ok()?;
+                return Some(SocketAddr::new(addr, DEFAULT_DNS_PORT));
+            };
+            let addr = addr.parse().ok()?;
+            let Some(scope_id) = scope.parse().ok().or_else(|| interface_index(scope)) else {
+                trace!(%addr, scope, "skipping nameserver on unknown interface");
+                return None;
+            };
+            Some(SocketAddrV6::new(addr, DEFAULT_DNS_PORT, 0, scope_id).into())
+        })
+        .collect()
 }
 
-fn parse_dns_response(response: DnsResponse, method: QueryMethod) -> Result<IpAddr, crate::Error> {
+fn parse_dns_response(
+    response: &DnsResponse,
+    method: &QueryMethod,
+) -> Result<IpAddr, crate::Error> {
+    if let QueryMethod::Custom(_, extractor) = method {
+        return extractor.extract(response.answers());
+    }
+    // Some servers return other facts alongside our IP address, so the first
+    // `TXT` string that is an IP address is used.
+    if *method == QueryMethod::TXT {
+        return txt_strings(response.answers())
+            .find_map(|txt| txt.parse().ok())
+            .ok_or(crate::Error::Addr);
+    }
     let answer = match response.answers().iter().next() {
         Some(answer) => answer,
         None => return Err(crate::Error::Addr),
     };
     match answer.data() {
-        Some(RData::A(addr)) if method == QueryMethod::A => Ok(IpAddr::V4(addr.0)),
-        Some(RData::AAAA(addr)) if method == QueryMethod::AAAA => Ok(IpAddr::V6(addr.0)),
-        Some(RData::TXT(txt)) if method == QueryMethod::TXT => match txt.iter().next() {
-            Some(addr_bytes) => Ok(str::from_utf8(&addr_bytes[..])?.parse()?),
-            None => Err(crate::Error::Addr),
-        },
-        _ => Err(ProtoError::from(ProtoErrorKind::Message("invalid response")).into()),
+        Some(RData::A(addr)) if *method == QueryMethod::A => Ok(IpAddr::V4(addr.0)),
+        Some(RData::AAAA(addr)) if *method == QueryMethod::AAAA => Ok(IpAddr::V6(addr.0)),
+        _ => Err(Error::InvalidResponse("answer does not match the query method").into()),
     }
 }
 
-fn resolve<'r>(server: IpAddr, port: u16, query: Query, method: QueryMethod) -> Resolutions<'r> {
+fn txt_strings(answers: &[Record]) -> impl Iterator<Item = &str> {
+    answers
+        .iter()
+        .filter_map(|answer| match answer.data() {
+            Some(RData::TXT(txt)) => Some(txt.iter()),
+            _ => None,
+        })
+        .flatten()
+        .filter_map(|bytes| str::from_utf8(bytes).ok())
+}
+
+/// Extracts our IP address from the `source <addr>:<port>` string returned by
+/// `debug.opendns.com`.
+#[cfg(feature = "opendns")]
+fn opendns_debug_source(answers: &[Record]) -> Result<IpAddr, crate::Error> {
+    txt_strings(answers)
+        .filter_map(|txt| txt.strip_prefix("source "))
+        .find_map(|source| {
+            source
+                .parse::<SocketAddr>()
+                .map(|addr| addr.ip())
+                .ok()
+                // IPv6 sources are written without brackets, so the port
+                // follows the last colon.
+                .or_else(|| source.rsplit_once(':')?.0.parse().ok())
+        })
+        .ok_or(crate::Error::Addr)
+}
+
+fn resolve(
+    server: Server<'_>,
+    version: Version,
+    query: Query,
+    method: QueryMethod,
+    options: QueryOptions,
+    pool: Option<Pool>,
+    delay: Duration,
+) -> Resolutions<'_> {
     let fut = async move {
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
         let name = query.name().clone();
-        let server = SocketAddr::new(server, port);
-        let mut query_opts = DnsRequestOptions::default();
-        query_opts.use_edns = true;
-        let response = dns_query(server, query, query_opts).await?;
-        let addr = parse_dns_response(response, method)?;
-        let details = Box::new(Details {
+        let class = query.query_class();
+        let (server, server_host) = match server {
+            Server::Addr(addr) => (addr, None),
+            Server::Host(host, port) => {
+                let version = method.server_version(version);
+                let addr = lookup_server(host, port, version, options, pool.as_ref()).await?;
+                (addr, Some(host.to_owned()))
+            }
+            Server::System => unreachable!("system servers are expanded before querying"),
+        };
+        let exchange = dns_query(server, query, options, pool.as_ref()).await?;
+        let response = exchange.response;
+        let server_identity =
+            match options.identity {
+                Some(IdentityMethod::Nsid) => response.extensions().as_ref().and_then(|edns| {
+                    match edns.option(EdnsCode::NSID) {
+                        Some(EdnsOption::Unknown(_, nsid)) => {
+                            Some(String::from_utf8_lossy(nsid).into_owned())
+                        }
+                        _ => None,
+                    }
+                }),
+                Some(method) => query_identity(server, method, options, pool.as_ref()).await,
+                None => None,
+            };
+        let details = Error::from_response(Box::new(Details {
             name,
             server,
+            server_host,
             method,
-        });
+            class,
+            transport: options.transport,
+            protections: exchange.protections,
+            response_time: exchange.response_time,
+            rcode: response.response_code(),
+            answers: response.answers().to_vec(),
+            edns: response.extensions().clone(),
+            server_identity,
+        }))?;
+        let addr = parse_dns_response(&response, &details.method)?;
         Ok((addr, crate::Details::from(details)))
     };
     Box::pin(stream::once(
-        fut.instrument(trace_span!("query server", %server)),
+        fut.instrument(trace_span!("query server", ?server)),
     ))
 }
+
+///////////////////////////////////////////////////////////////////////////////
+// Interception
+
+/// Checks whether queries to the builtin DNS providers are answered by the
+/// servers they are sent to.
+///
+/// Some networks transparently redirect DNS traffic to a local resolver, in
+/// which case the whoami answers of the builtin resolvers describe that
+/// resolver rather than us. Each provider server matching `version` is sent
+/// queries that only it can answer correctly, and any response that could not
+/// have come from it is recorded as [`Evidence`].
+///
+/// Servers that do not respond at all are reported as not answered, since
+/// dropped traffic alone does not indicate interception.
+#[cfg(any(feature = "cloudflare", feature = "google"))]
+#[cfg_attr(docsrs, doc(cfg(any(feature = "cloudflare", feature = "google"))))]
+pub async fn detect_interception(version: Version) -> Vec<InterceptionCheck> {
+    let checks = INTERCEPTION_PROBES
+        .iter()
+        .flat_map(|(servers, probe)| {
+            servers.iter().filter_map(move |server| match server {
+                Server::Addr(addr) if version.matches(addr.ip()) => Some((*addr, *probe)),
+                _ => None,
+            })
+        })
+        .map(|(server, probe)| {
+            probe
+                .check(server)
+                .instrument(trace_span!("check interception", %server))
+        });
+    future::join_all(checks).await
+}
+
+/// The result of checking a DNS server for interception.
+#[cfg(any(feature = "cloudflare", feature = "google"))]
+#[cfg_attr(docsrs, doc(cfg(any(feature = "cloudflare", feature = "google"))))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterceptionCheck {
+    server: SocketAddr,
+    answered: bool,
+    evidence: Vec<Evidence>,
+}
+
+#[cfg(any(feature = "cloudflare", feature = "google"))]
+impl InterceptionCheck {
+    /// The server the queries were sent to.
+    #[must_use]
+    pub fn server(&self) -> SocketAddr {
+        self.server
+    }
+
+    /// Whether any of the queries were answered.
+    #[must_use]
+    pub fn answered(&self) -> bool {
+        self.answered
+    }
+
+    /// Evidence that the queries were answered by a different server.
+    #[must_use]
+    pub fn evidence(&self) -> &[Evidence] {
+        &self.evidence
+    }
+
+    /// Returns `true` if there is any evidence of interception.
+    #[must_use]
+    pub fn is_intercepted(&self) -> bool {
+        !self.evidence.is_empty()
+    }
+
+    async fn query(
+        &mut self,
+        name: &str,
+        class: DNSClass,
+        record_type: RecordType,
+        recursion_desired: bool,
+    ) -> Option<DnsResponse> {
+        let mut query = Query::query(Name::from_ascii(name).ok()?, record_type);
+        query.set_query_class(class);
+        let mut options = QueryOptions::new();
+        options.message.recursion_desired = recursion_desired;
+        match dns_query(self.server, query, options, None).await {
+            Ok(exchange) => {
+                self.answered = true;
+                Some(exchange.response)
+            }
+            Err(err) => {
+                trace!(%err, name, "interception probe failed");
+                None
+            }
+        }
+    }
+}
+
+/// Evidence that a DNS query was answered by a server other than the one it
+/// was sent to.
+#[cfg(any(feature = "cloudflare", feature = "google"))]
+#[cfg_attr(docsrs, doc(cfg(any(feature = "cloudflare", feature = "google"))))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Evidence {
+    /// A query the server always answers returned an unexpected response
+    /// code.
+    UnexpectedRcode {
+        /// The name that was queried.
+        name: String,
+        /// The response code that was returned.
+        rcode: ResponseCode,
+    },
+    /// A query the server always answers returned no usable answer.
+    MissingAnswer {
+        /// The name that was queried.
+        name: String,
+    },
+    /// An authoritative server answered without the authoritative answer
+    /// flag, or claimed to offer recursion.
+    NotAuthoritative {
+        /// The name that was queried.
+        name: String,
+    },
+    /// An authoritative server answered a name outside of its zones.
+    RecursiveAnswer {
+        /// The name that was queried.
+        name: String,
+    },
+    /// A whoami answer contained a client subnet, which is only added when a
+    /// recursive resolver forwards the query.
+    ClientSubnet {
+        /// The name that was queried.
+        name: String,
+    },
+}
+
+/// Servers checked by [`detect_interception`] and how they are checked.
+#[cfg(any(feature = "cloudflare", feature = "google"))]
+const INTERCEPTION_PROBES: &[(&[Server<'static>], Probe)] = &[
+    #[cfg(feature = "cloudflare")]
+    (CLOUDFLARE_V4_SERVERS, Probe::Cloudflare),
+    #[cfg(feature = "cloudflare")]
+    (CLOUDFLARE_V6_SERVERS, Probe::Cloudflare),
+    #[cfg(feature = "google")]
+    (GOOGLE_V4_SERVERS, Probe::GoogleAuthoritative),
+    #[cfg(feature = "google")]
+    (GOOGLE_V6_SERVERS, Probe::GoogleAuthoritative),
+];
+
+/// A name every Cloudflare site answers with its airport code.
+#[cfg(feature = "cloudflare")]
+const CLOUDFLARE_ID_NAME: &str = "id.server";
+
+/// A name outside of the zones served by the authoritative servers probed.
+#[cfg(feature = "google")]
+const FOREIGN_NAME: &str = "example.com";
+
+#[cfg(any(feature = "cloudflare", feature = "google"))]
+#[derive(Debug, Clone, Copy)]
+enum Probe {
+    /// Cloudflare answers its own `CH` class whoami and `id.server` names,
+    /// which a local resolver will not.
+    #[cfg(feature = "cloudflare")]
+    Cloudflare,
+    /// Google's authoritative servers answer their own zone authoritatively,
+    /// without recursion or a client subnet, and refuse everything else.
+    #[cfg(feature = "google")]
+    GoogleAuthoritative,
+}
+
+#[cfg(any(feature = "cloudflare", feature = "google"))]
+impl Probe {
+    async fn check(self, server: SocketAddr) -> InterceptionCheck {
+        let mut check = InterceptionCheck {
+            server,
+            answered: false,
+            evidence: Vec::new(),
+        };
+        match self {
+            #[cfg(feature = "cloudflare")]
+            Self::Cloudflare => {
+                let name = CLOUDFLARE_WHOAMI_NAME;
+                if let Some(response) = check.query(name, DNSClass::CH, RecordType::TXT, true).await
+                {
+                    check.evidence.extend(cloudflare_whoami_evidence(&response));
+                }
+                let name = CLOUDFLARE_ID_NAME;
+                if let Some(response) = check.query(name, DNSClass::CH, RecordType::TXT, true).await
+                {
+                    check.evidence.extend(cloudflare_id_evidence(&response));
+                }
+            }
+            #[cfg(feature = "google")]
+            Self::GoogleAuthoritative => {
+                let name = GOOGLE_WHOAMI_NAME;
+                if let Some(response) = check
+                    .query(name, DNSClass::IN, RecordType::TXT, false)
+                    .await
+                {
+                    check.evidence.extend(google_whoami_evidence(&response));
+                }
+                let name = FOREIGN_NAME;
+                if let Some(response) = check.query(name, DNSClass::IN, RecordType::A, false).await
+                {
+                    check.evidence.extend(foreign_name_evidence(&response));
+                }
+            }
+        }
+        check
+    }
+}
+
+/// Evidence from a response to [`CLOUDFLARE_WHOAMI_NAME`], which Cloudflare
+/// always answers with our IP address.
+#[cfg(feature = "cloudflare")]
+fn cloudflare_whoami_evidence(response: &DnsResponse) -> Option<Evidence> {
+    let name = CLOUDFLARE_WHOAMI_NAME.to_owned();
+    if response.response_code() != ResponseCode::NoError {
+        Some(Evidence::UnexpectedRcode {
+            name,
+            rcode: response.response_code(),
+        })
+    } else if !txt_strings(response.answers()).any(|txt| txt.parse::<IpAddr>().is_ok()) {
+        Some(Evidence::MissingAnswer { name })
+    } else {
+        None
+    }
+}
+
+/// Evidence from a response to [`CLOUDFLARE_ID_NAME`], which Cloudflare always
+/// answers with the airport code of the site.
+#[cfg(feature = "cloudflare")]
+fn cloudflare_id_evidence(response: &DnsResponse) -> Option<Evidence> {
+    let name = CLOUDFLARE_ID_NAME.to_owned();
+    txt_strings(response.answers())
+        .next()
+        .is_none()
+        .then_some(Evidence::MissingAnswer { name })
+}
+
+/// Evidence from a response to [`GOOGLE_WHOAMI_NAME`], which Google's
+/// authoritative servers answer authoritatively and without a client subnet.
+#[cfg(feature = "google")]
+fn google_whoami_evidence(response: &DnsResponse) -> Vec<Evidence> {
+    let name = GOOGLE_WHOAMI_NAME;
+    let mut evidence = Vec::new();
+    if !response.authoritative() || response.recursion_available() {
+        evidence.push(Evidence::NotAuthoritative {
+            name: name.to_owned(),
+        });
+    }
+    if GoogleWhoami::from_answers(response.answers())
+        .and_then(|whoami| whoami.client_subnet())
+        .is_some()
+    {
+        evidence.push(Evidence::ClientSubnet {
+            name: name.to_owned(),
+        });
+    }
+    evidence
+}
+
+/// Evidence from a response to [`FOREIGN_NAME`], which Google's authoritative
+/// servers refuse to answer.
+#[cfg(feature = "google")]
+fn foreign_name_evidence(response: &DnsResponse) -> Option<Evidence> {
+    let name = FOREIGN_NAME.to_owned();
+    (!response.answers().is_empty()).then_some(Evidence::RecursiveAnswer { name })
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Pool
+
+/// A pool of DNS clients shared between resolutions.
+///
+/// Clients are kept per server address, [`Transport`] and timeout, so
+/// repeatedly querying the same server reuses its client rather than creating
+/// a new one (and spawning a new background task) for every query. Resolvers
+/// with different timeouts do not share clients, as the timeout is set when a
+/// client is created. A client that fails a query is dropped from the pool.
+///
+/// The pool also keeps the DNS cookies exchanged with each server, see
+/// [`Resolver::with_cookies`].
+///
+/// Cloning a pool is cheap and the clone shares the same clients.
+#[derive(Clone, Default)]
+pub struct Pool {
+    clients: Arc<Mutex<HashMap<ClientKey, AsyncClient>>>,
+    cookies: Arc<Mutex<HashMap<SocketAddr, Cookie>>>,
+}
+
+/// The server address, transport and timeout a pooled client is created with.
+type ClientKey = (SocketAddr, Transport, Duration);
+
+impl Pool {
+    /// Create a new empty pool.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    async fn client(
+        &self,
+        server: SocketAddr,
+        options: QueryOptions,
+    ) -> Result<AsyncClient, Error> {
+        let key = (server, options.transport, options.timeout);
+        if let Some(client) = self.lock().get(&key) {
+            return Ok(client.clone());
+        }
+        let client = connect(server, options).await?;
+        self.lock().insert(key, client.clone());
+        Ok(client)
+    }
+
+    fn remove(&self, server: SocketAddr, options: QueryOptions) {
+        self.lock()
+            .remove(&(server, options.transport, options.timeout));
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<ClientKey, AsyncClient>> {
+        self.clients.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The cookie to send to a server, keeping the same client cookie for
+    /// every query to it.
+    fn cookie(&self, server: SocketAddr) -> Cookie {
+        self.lock_cookies()
+            .entry(server)
+            .or_insert_with(Cookie::new)
+            .clone()
+    }
+
+    fn set_cookie(&self, server: SocketAddr, cookie: Cookie) {
+        self.lock_cookies().insert(server, cookie);
+    }
+
+    fn lock_cookies(&self) -> MutexGuard<'_, HashMap<SocketAddr, Cookie>> {
+        self.cookies.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl fmt::Debug for Pool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut servers: Vec<_> = self.lock().keys().copied().collect();
+        servers.sort_unstable_by_key(|(server, _, _)| *server);
+        f.debug_struct("Pool").field("clients", &servers).finish()
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use hickory_proto::rr::rdata::{A, AAAA, TXT};
+
+    use super::*;
+
+    fn response(answers: Vec<RData>) -> DnsResponse {
+        let name = Name::from_ascii("example.com.").unwrap();
+        let mut message = Message::new();
+        message.set_message_type(MessageType::Response);
+        for rdata in answers {
+            message.add_answer(Record::from_rdata(name.clone(), 0, rdata));
+        }
+        DnsResponse::from_message(message).unwrap()
+    }
+
+    fn txt(strings: &[&str]) -> RData {
+        RData::TXT(TXT::new(strings.iter().map(|s| (*s).to_owned()).collect()))
+    }
+
+    fn cookie_response(cookie: Option<Vec<u8>>) -> DnsResponse {
+        let mut message = Message::new();
+        message.set_message_type(MessageType::Response);
+        let mut edns = Edns::new();
+        if let Some(cookie) = cookie {
+            edns.options_mut()
+                .insert(EdnsOption::Unknown(EdnsCode::Cookie.into(), cookie));
+        }
+        message.set_edns(edns);
+        DnsResponse::from_message(message).unwrap()
+    }
+
+    #[test]
+    fn cookies() {
+        let query = Query::query(Name::from_ascii("example.com.").unwrap(), RecordType::A);
+        let options = QueryOptions {
+            cookies: true,
+            ..QueryOptions::new()
+        };
+        let mut cookie = Cookie::new();
+        let mut echoed = cookie.client.to_vec();
+
+        // Servers that do not support cookies are accepted until they return
+        // one.
+        let protections =
+            verify_response(&cookie_response(None), &query, Some(&mut cookie), options).unwrap();
+        assert!(!protections.cookie());
+
+        echoed.extend([7; 16]);
+        let protections = verify_response(
+            &cookie_response(Some(echoed.clone())),
+            &query,
+            Some(&mut cookie),
+            options,
+        )
+        .unwrap();
+        assert!(protections.cookie());
+        assert_eq!(cookie.server.as_deref(), Some(&[7; 16][..]));
+        assert!(
+            verify_response(&cookie_response(None), &query, Some(&mut cookie), options).is_err()
+        );
+
+        echoed[0] ^= 1;
+        assert!(verify_response(
+            &cookie_response(Some(echoed)),
+            &query,
+            Some(&mut cookie),
+            options
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn resolv_conf() {
+        let resolv_conf = "\
+# nameserver 192.0.2.9
+nameserver 192.0.2.1
+nameserver\t2001:db8::1
+nameserver fe80::1%eth0
+nameserver fe80::2%3
+nameserver fe80::3%wlan9
+nameservers 192.0.2.2
+search example.com
+";
+        let index = |name: &str| (name == "eth0").then_some(2);
+        let expected: [SocketAddr; 4] = [
+            "192.0.2.1:53".parse().unwrap(),
+            "[2001:db8::1]:53".parse().unwrap(),
+            SocketAddrV6::new("fe80::1".parse().unwrap(), 53, 0, 2).into(),
+            SocketAddrV6::new("fe80::2".parse().unwrap(), 53, 0, 3).into(),
+        ];
+        assert_eq!(parse_resolv_conf(resolv_conf, index), expected);
+    }
+
+    #[test]
+    fn google_whoami() {
+        // Queried through a recursive resolver forwarding a client subnet.
+        let answers = response(vec![
+            txt(&["172.253.211.1"]),
+            txt(&["edns0-client-subnet 192.0.2.0/24"]),
+        ]);
+        let whoami = GoogleWhoami::from_answers(answers.answers()).unwrap();
+        assert_eq!(
+            whoami.requester(),
+            "172.253.211.1".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            whoami.client_subnet(),
+            Some(("192.0.2.0".parse().unwrap(), 24))
+        );
+
+        let answers = response(vec![
+            txt(&["edns0-client-subnet 2001:db8::/56"]),
+            txt(&["2001:4860:4000::1"]),
+        ]);
+        let whoami = GoogleWhoami::from_answers(answers.answers()).unwrap();
+        assert_eq!(
+            whoami.requester(),
+            "2001:4860:4000::1".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            whoami.client_subnet(),
+            Some(("2001:db8::".parse().unwrap(), 56))
+        );
+
+        // Queried directly, or with a malformed subnet.
+        let answers = response(vec![txt(&["192.0.2.1"])]);
+        let whoami = GoogleWhoami::from_answers(answers.answers()).unwrap();
+        assert_eq!(whoami.client_subnet(), None);
+        let answers = response(vec![
+            txt(&["192.0.2.1"]),
+            txt(&["edns0-client-subnet 192.0.2.0"]),
+        ]);
+        let whoami = GoogleWhoami::from_answers(answers.answers()).unwrap();
+        assert_eq!(whoami.client_subnet(), None);
+
+        let answers = response(vec![txt(&["edns0-client-subnet 192.0.2.0/24"])]);
+        assert_eq!(GoogleWhoami::from_answers(answers.answers()), None);
+    }
+
+    fn client_subnet(data: &[u8]) -> Option<ClientSubnet> {
+        ClientSubnet::from_option(&EdnsOption::Unknown(EdnsCode::Subnet.into(), data.to_vec()))
+    }
+
+    #[test]
+    fn client_subnet_option() {
+        let subnet = client_subnet(&[0, 1, 24, 0, 192, 0, 2]).unwrap();
+        assert_eq!(subnet.addr(), "192.0.2.0".parse::<IpAddr>().unwrap());
+        assert_eq!((subnet.source_prefix(), subnet.scope_prefix()), (24, 0));
+        // Addresses are truncated to the bytes covered by the source prefix.
+        let subnet = client_subnet(&[0, 1, 16, 16, 198, 51]).unwrap();
+        assert_eq!(subnet.addr(), "198.51.0.0".parse::<IpAddr>().unwrap());
+        assert_eq!((subnet.source_prefix(), subnet.scope_prefix()), (16, 16));
+        let subnet = client_subnet(&[0, 2, 56, 48, 0x20, 0x01, 0x0d, 0xb8, 0, 0, 0x12]).unwrap();
+        assert_eq!(
+            subnet.addr(),
+            "2001:db8:0:1200::".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!((subnet.source_prefix(), subnet.scope_prefix()), (56, 48));
+        let subnet = client_subnet(&[0, 2, 0, 0]).unwrap();
+        assert_eq!(subnet.addr(), "::".parse::<IpAddr>().unwrap());
+
+        // Longer addresses than the family allows, unknown families and
+        // truncated headers are rejected.
+        assert_eq!(client_subnet(&[0, 1, 32, 0, 192, 0, 2, 1, 1]), None);
+        assert_eq!(client_subnet(&[0, 3, 24, 0, 192, 0, 2]), None);
+        assert_eq!(client_subnet(&[0, 1, 24]), None);
+    }
+
+    #[test]
+    fn client_subnet_wire() {
+        // Options decoded from the wire are parsed by hickory first.
+        let mut message = Message::new();
+        message.set_message_type(MessageType::Response);
+        let mut edns = Edns::new();
+        edns.options_mut().insert(EdnsOption::Unknown(
+            EdnsCode::Subnet.into(),
+            vec![0, 2, 48, 0, 0x20, 0x01, 0x0d, 0xb8, 0xab, 0xcd],
+        ));
+        message.set_edns(edns);
+        let message = Message::from_vec(&message.to_vec().unwrap()).unwrap();
+        let option = message
+            .extensions()
+            .as_ref()
+            .unwrap()
+            .option(EdnsCode::Subnet);
+        let subnet = ClientSubnet::from_option(option.unwrap()).unwrap();
+        assert_eq!(subnet.addr(), "2001:db8:abcd::".parse::<IpAddr>().unwrap());
+        assert_eq!((subnet.source_prefix(), subnet.scope_prefix()), (48, 0));
+    }
+
+    fn details(rcode: ResponseCode) -> Box<Details> {
+        let mut edns = Edns::new();
+        edns.options_mut()
+            .insert(EdnsOption::Unknown(EdnsCode::NSID.into(), b"ns1".to_vec()));
+        Box::new(Details {
+            name: Name::from_ascii("example.com.").unwrap(),
+            server: ([192, 0, 2, 53], 53).into(),
+            server_host: None,
+            method: QueryMethod::A,
+            class: DNSClass::IN,
+            transport: Transport::Udp,
+            protections: Protections {
+                case_randomization: false,
+                cookie: false,
+                source_matching: true,
+            },
+            response_time: Duration::from_millis(20),
+            rcode,
+            answers: Vec::new(),
+            edns: Some(edns),
+            server_identity: None,
+        })
+    }
+
+    #[test]
+    fn error_details() {
+        assert!(Error::from_response(details(ResponseCode::NoError)).is_ok());
+        let err = Error::from_response(details(ResponseCode::NXDomain)).unwrap_err();
+        assert!(matches!(err, Error::NxDomain(_)));
+        let err = Error::from_response(details(ResponseCode::Refused)).unwrap_err();
+        assert!(matches!(err, Error::Rcode(5, _)));
+        // What the server returned is kept with the error.
+        let details = err.details().unwrap();
+        assert_eq!(details.rcode(), ResponseCode::Refused);
+        assert_eq!(details.response_time(), Duration::from_millis(20));
+        assert!(details
+            .edns
+            .as_ref()
+            .unwrap()
+            .option(EdnsCode::NSID)
+            .is_some());
+        assert!(Error::Timeout.details().is_none());
+    }
+
+    /// Answers one `A` query with `rcode`, refusing queries without the
+    /// recursion desired bit as a recursive resolver may.
+    async fn bootstrap_server(rcode: ResponseCode) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+            let query = Message::from_vec(&buf[..len]).unwrap();
+            let mut response = Message::new();
+            response
+                .set_id(query.id())
+                .set_message_type(MessageType::Response)
+                .add_queries(query.queries().to_vec());
+            if !query.recursion_desired() {
+                response.set_response_code(ResponseCode::Refused);
+            } else if rcode == ResponseCode::NoError {
+                let name = query.queries()[0].name().clone();
+                response.add_answer(Record::from_rdata(name, 0, RData::A(A::new(192, 0, 2, 53))));
+            } else {
+                response.set_response_code(rcode);
+            }
+            socket
+                .send_to(&response.to_vec().unwrap(), peer)
+                .await
+                .unwrap();
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn bootstrap_options() {
+        // The options of the whoami query are not used for the bootstrap
+        // server, which must recurse.
+        let mut options = QueryOptions::new();
+        options.message.recursion_desired = false;
+        options.case_randomization = true;
+        options.cookies = true;
+        options.identity = Some(IdentityMethod::Nsid);
+        let server = bootstrap_server(ResponseCode::NoError).await;
+        let addrs = lookup_host_with(server, "ns1.example.com", Version::V4, options, None).await;
+        assert_eq!(addrs.unwrap(), ["192.0.2.53".parse::<IpAddr>().unwrap()]);
+
+        // Error responses fail the lookup rather than returning no address.
+        let server = bootstrap_server(ResponseCode::ServFail).await;
+        let addrs = lookup_host_with(server, "ns1.example.com", Version::V4, options, None).await;
+        assert!(matches!(addrs, Err(Error::ServerLookup(_))));
+    }
+
+    #[tokio::test]
+    async fn pool_timeouts() {
+        let pool = Pool::new();
+        let server = ([127, 0, 0, 1], 9).into();
+        let mut options = QueryOptions::new();
+        pool.client(server, options).await.unwrap();
+        pool.client(server, options).await.unwrap();
+        assert_eq!(pool.lock().len(), 1);
+        // Clients are created with their timeout, so they are not shared by
+        // resolvers with another one.
+        options.timeout = Duration::from_secs(1);
+        pool.client(server, options).await.unwrap();
+        assert_eq!(pool.lock().len(), 2);
+        pool.remove(server, options);
+        assert_eq!(pool.lock().len(), 1);
+    }
+
+    #[test]
+    fn address_answers() {
+        let v4 = response(vec![RData::A(A::new(192, 0, 2, 1))]);
+        assert_eq!(
+            parse_dns_response(&v4, &QueryMethod::A).unwrap(),
+            "192.0.2.1".parse::<IpAddr>().unwrap()
+        );
+        let v6 = response(vec![RData::AAAA(AAAA::new(
+            0x2001, 0xdb8, 0, 0, 0, 0, 0, 1,
+        ))]);
+        assert_eq!(
+            parse_dns_response(&v6, &QueryMethod::AAAA).unwrap(),
+            "2001:db8::1".parse::<IpAddr>().unwrap()
+        );
+        assert!(parse_dns_response(&v6, &QueryMethod::A).is_err());
+        assert!(parse_dns_response(&response(Vec::new()), &QueryMethod::A).is_err());
+    }
+
+    #[test]
+    fn server_version() {
+        // Akamai answers with the address a query is sent from, so its server
+        // must be reached over the version of the record queried.
+        assert_eq!(QueryMethod::A.server_version(Version::Any), Version::V4);
+        assert_eq!(QueryMethod::AAAA.server_version(Version::Any), Version::V6);
+        assert_eq!(QueryMethod::AAAA.server_version(Version::V4), Version::V4);
+        assert_eq!(QueryMethod::TXT.server_version(Version::Any), Version::Any);
+        #[cfg(feature = "akamai")]
+        assert!(matches!(AKAMAI_SERVERS, [Server::Host(..)]));
+    }
+
+    #[test]
+    #[cfg(feature = "opendns")]
+    fn opendns_debug() {
+        let method = QueryMethod::Custom(
+            RecordType::TXT,
+            Extractor::new_static(&opendns_debug_source),
+        );
+        let v4 = response(vec![
+            txt(&["server r2003.ams"]),
+            txt(&["flags 20 0 2f4 7950800000000000000"]),
+            txt(&["source 192.0.2.1:50123"]),
+        ]);
+        assert_eq!(
+            parse_dns_response(&v4, &method).unwrap(),
+            "192.0.2.1".parse::<IpAddr>().unwrap()
+        );
+        let v6 = response(vec![
+            txt(&["server r2003.ams"]),
+            txt(&["source 2001:db8::1:5353"]),
+        ]);
+        assert_eq!(
+            parse_dns_response(&v6, &method).unwrap(),
+            "2001:db8::1".parse::<IpAddr>().unwrap()
+        );
+        let missing = response(vec![txt(&["server r2003.ams"])]);
+        assert!(parse_dns_response(&missing, &method).is_err());
+    }
+
+    #[cfg(any(feature = "cloudflare", feature = "google"))]
+    fn with_header(response: DnsResponse, f: impl FnOnce(&mut Message)) -> DnsResponse {
+        let mut message = response.into_message();
+        f(&mut message);
+        DnsResponse::from_message(message).unwrap()
+    }
+
+    #[test]
+    #[cfg(feature = "cloudflare")]
+    fn cloudflare_evidence() {
+        let whoami = response(vec![txt(&["192.0.2.1"])]);
+        assert_eq!(cloudflare_whoami_evidence(&whoami), None);
+        let refused = with_header(response(vec![]), |message| {
+            message.set_response_code(ResponseCode::Refused);
+        });
+        assert_eq!(
+            cloudflare_whoami_evidence(&refused),
+            Some(Evidence::UnexpectedRcode {
+                name: CLOUDFLARE_WHOAMI_NAME.to_owned(),
+                rcode: ResponseCode::Refused,
+            })
+        );
+        let missing = Some(Evidence::MissingAnswer {
+            name: CLOUDFLARE_WHOAMI_NAME.to_owned(),
+        });
+        assert_eq!(cloudflare_whoami_evidence(&response(vec![])), missing);
+        let not_addr = response(vec![txt(&["resolver.example"])]);
+        assert_eq!(cloudflare_whoami_evidence(&not_addr), missing);
+
+        let id = response(vec![txt(&["AMS"])]);
+        assert_eq!(cloudflare_id_evidence(&id), None);
+        assert_eq!(
+            cloudflare_id_evidence(&response(vec![])),
+            Some(Evidence::MissingAnswer {
+                name: CLOUDFLARE_ID_NAME.to_owned(),
+            })
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "google")]
+    fn google_evidence() {
+        let authoritative = |response| {
+            with_header(response, |message| {
+                message.set_authoritative(true);
+            })
+        };
+        let not_authoritative = Evidence::NotAuthoritative {
+            name: GOOGLE_WHOAMI_NAME.to_owned(),
+        };
+        let client_subnet = Evidence::ClientSubnet {
+            name: GOOGLE_WHOAMI_NAME.to_owned(),
+        };
+
+        let whoami = authoritative(response(vec![txt(&["192.0.2.1"])]));
+        assert_eq!(google_whoami_evidence(&whoami), []);
+        let forwarded = response(vec![
+            txt(&["172.253.211.1"]),
+            txt(&["edns0-client-subnet 192.0.2.0/24"]),
+        ]);
+        assert_eq!(
+            google_whoami_evidence(&forwarded),
+            [not_authoritative.clone(), client_subnet.clone()]
+        );
+        let forwarded = authoritative(forwarded);
+        assert_eq!(google_whoami_evidence(&forwarded), [client_subnet]);
+        let recursive = with_header(whoami, |message| {
+            message.set_recursion_available(true);
+        });
+        assert_eq!(google_whoami_evidence(&recursive), [not_authoritative]);
+
+        let refused = authoritative(response(vec![]));
+        assert_eq!(foreign_name_evidence(&refused), None);
+        let answered = response(vec![RData::A(A::new(192, 0, 2, 1))]);
+        assert_eq!(
+            foreign_name_evidence(&answered),
+            Some(Evidence::RecursiveAnswer {
+                name: FOREIGN_NAME.to_owned(),
+            })
+        );
+    }
+}
@@ -40,12 +40,6 @@ impl Error {
     }
 }
 
-impl From<reqwest::Error> for Error {
-    fn from(error: reqwest::Error) -> Self {
-        Into::<crate::http::Error>::into(error).into()
-    }
-}
-
 impl From<Utf8Error> for Error {
     fn from(_: Utf8Error) -> Self {
         Self::Addr
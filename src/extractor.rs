@@ -0,0 +1,56 @@
+use std::fmt;
+use std::sync::Arc;
+
+/// A user-supplied function `F` extracting our IP address from a response.
+///
+/// Each resolver module names the function it takes, see `dns::Extractor` and
+/// `http::Extractor`. The function is either static, so that extractors can
+/// be used in constants, or shared between clones of the extractor.
+pub struct Extractor<F: ?Sized + 'static>(Kind<F>);
+
+enum Kind<F: ?Sized + 'static> {
+    Static(&'static F),
+    Shared(Arc<F>),
+}
+
+impl<F: ?Sized> Extractor<F> {
+    /// Create a new extractor from a static function.
+    #[must_use]
+    pub const fn new_static(f: &'static F) -> Self {
+        Self(Kind::Static(f))
+    }
+
+    pub(crate) fn shared(f: Arc<F>) -> Self {
+        Self(Kind::Shared(f))
+    }
+
+    pub(crate) fn get(&self) -> &F {
+        match &self.0 {
+            Kind::Static(f) => f,
+            Kind::Shared(f) => f,
+        }
+    }
+}
+
+impl<F: ?Sized> Clone for Extractor<F> {
+    fn clone(&self) -> Self {
+        match &self.0 {
+            Kind::Static(f) => Self(Kind::Static(f)),
+            Kind::Shared(f) => Self(Kind::Shared(f.clone())),
+        }
+    }
+}
+
+impl<F: ?Sized> PartialEq for Extractor<F> {
+    /// Extractors are equal if they share the same function.
+    fn eq(&self, other: &Self) -> bool {
+        let (f, other): (*const F, *const F) = (self.get(), other.get());
+        f.cast::<()>() == other.cast::<()>()
+    }
+}
+
+impl<F: ?Sized> fmt::Debug for Extractor<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extractor").finish_non_exhaustive()
+    }
+}
@@ -1,9 +1,16 @@
 use std::borrow::Cow;
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::fmt;
 use std::future::Future;
+use std::io;
+use std::iter;
 use std::net::{IpAddr, SocketAddr};
 use std::pin::Pin;
 use std::str;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, PoisonError};
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
 
 use futures_core::Stream;
 use futures_util::future::BoxFuture;
@@ -13,7 +20,21 @@ use futures_util::{future, ready, stream};
 //     client::Builder,
 // };
 use pin_project_lite::pin_project;
-use reqwest::Url;
+use regex::Regex;
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE, RETRY_AFTER, USER_AGENT},
+    StatusCode, Url,
+};
+use ring::digest;
+use rustls::{
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        WebPkiServerVerifier,
+    },
+    crypto::{CryptoProvider, WebPkiSupportedAlgorithms},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    CertificateError, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
 use thiserror::Error;
 use tracing::trace_span;
 use tracing_futures::Instrument;
@@ -27,6 +48,8 @@ use crate::{Resolutions, Version};
 ///////////////////////////////////////////////////////////////////////////////
 // Hardcoded resolvers
 
+const MAX_BODY_LEN: usize = 64 * 1024;
+
 /// All builtin HTTP/HTTPS resolvers.
 pub const ALL: &dyn crate::Resolver<'static> = &&[HTTP, HTTPS];
 
@@ -52,6 +75,8 @@ pub const HTTPS: &dyn crate::Resolver<'static> = &&[
     HTTPS_MY_IP_IO,
     #[cfg(feature = "seeip-org")]
     HTTPS_SEEIP_ORG,
+    #[cfg(feature = "cloudflare")]
+    HTTPS_CLOUDFLARE_TRACE,
 ];
 
 /// `http://api.ipify.org` HTTP resolver options
@@ -78,18 +103,169 @@ pub const HTTPS_MY_IP_IO: &dyn crate::Resolver<'static> =
 pub const HTTPS_SEEIP_ORG: &dyn crate::Resolver<'static> =
     &Resolver::new_static("https://ip.seeip.org", ExtractMethod::PlainText);
 
+/// All builtin resolvers also returning the location and network of our IP
+/// address, see [`Details::metadata`].
+///
+/// These providers restrict the use of their free services, so they are only
+/// available with their own features and are not included in [`ALL`].
+pub const METADATA: &dyn crate::Resolver<'static> = &&[
+    #[cfg(feature = "ip-api-com")]
+    HTTP_IP_API_COM,
+    #[cfg(feature = "ipinfo-io")]
+    HTTPS_IPINFO_IO,
+    #[cfg(feature = "ifconfig-co")]
+    HTTPS_IFCONFIG_CO,
+];
+
+/// `http://ip-api.com/json` HTTP resolver options
+///
+/// The free service is for non-commercial use only, is only available over
+/// HTTP, and is limited to 45 requests per minute.
+#[cfg(feature = "ip-api-com")]
+#[cfg_attr(docsrs, doc(cfg(feature = "ip-api-com")))]
+pub const HTTP_IP_API_COM: &dyn crate::Resolver<'static> = &Resolver::new_static(
+    "http://ip-api.com/json",
+    ExtractMethod::JsonPointer(Cow::Borrowed("/query")),
+)
+.with_metadata(MetadataFormat::IpApi)
+.with_rate_limit(RateLimit::new(45, Duration::from_secs(60)));
+
+/// `https://ipinfo.io/json` HTTPS resolver options
+///
+/// Requests without an API token (see [`Resolver::with_headers`]) are limited
+/// to 50,000 per month.
+#[cfg(feature = "ipinfo-io")]
+#[cfg_attr(docsrs, doc(cfg(feature = "ipinfo-io")))]
+pub const HTTPS_IPINFO_IO: &dyn crate::Resolver<'static> = &Resolver::new_static(
+    "https://ipinfo.io/json",
+    ExtractMethod::JsonPointer(Cow::Borrowed("/ip")),
+)
+.with_metadata(MetadataFormat::IpInfo);
+
+/// `https://ifconfig.co/json` HTTPS resolver options
+///
+/// Automated requests are limited to one per minute.
+#[cfg(feature = "ifconfig-co")]
+#[cfg_attr(docsrs, doc(cfg(feature = "ifconfig-co")))]
+pub const HTTPS_IFCONFIG_CO: &dyn crate::Resolver<'static> = &Resolver::new_static(
+    "https://ifconfig.co/json",
+    ExtractMethod::JsonPointer(Cow::Borrowed("/ip")),
+)
+.with_metadata(MetadataFormat::IfconfigCo)
+.with_rate_limit(RateLimit::new(1, Duration::from_secs(60)));
+
+/// `https://cloudflare.com/cdn-cgi/trace` HTTPS resolver options
+///
+/// See [`Details::cloudflare_trace`] for the other facts returned.
+#[cfg(feature = "cloudflare")]
+#[cfg_attr(docsrs, doc(cfg(feature = "cloudflare")))]
+pub const HTTPS_CLOUDFLARE_TRACE: &dyn crate::Resolver<'static> = &Resolver::new_static(
+    "https://cloudflare.com/cdn-cgi/trace",
+    ExtractMethod::KeyValue(Cow::Borrowed("ip")),
+);
+
 ///////////////////////////////////////////////////////////////////////////////
 // Error
 
 /// HTTP resolver error
 #[derive(Debug, Error)]
+#[non_exhaustive]
 pub enum Error {
-    /// Client error.
-    #[error("{0}")]
-    Client(#[from] reqwest::Error),
-    /// URL parsing error.
-    #[error("{0}")]
-    Url(#[from] url::ParseError),
+    /// The request timed out.
+    #[error("request timed out")]
+    Timeout,
+    /// The server refused the connection.
+    #[error("connection refused")]
+    ConnectionRefused,
+    /// Failed to connect to the server.
+    #[error("failed to connect")]
+    Connect(#[source] Box<dyn StdError + Send + Sync + 'static>),
+    /// The TLS handshake with the server failed.
+    #[error("TLS handshake failed")]
+    Tls(#[source] Box<dyn StdError + Send + Sync + 'static>),
+    /// The TLS settings of the resolver were invalid.
+    #[error("invalid TLS settings")]
+    TlsConfig(#[source] Box<dyn StdError + Send + Sync + 'static>),
+    /// The server returned an unsuccessful HTTP status code.
+    #[error("server returned HTTP status {0}")]
+    Status(u16),
+    /// The request was rate limited, either by the server or by the rate
+    /// limit of the resolver (see [`Resolver::with_rate_limit`]).
+    #[error("rate limited")]
+    RateLimited {
+        /// How long to wait before retrying, if known.
+        retry_after: Option<Duration>,
+    },
+    /// The response content type did not match the extract method.
+    #[error("unexpected content type {0:?}")]
+    ContentType(Option<String>),
+    /// The JSON pointer of [`ExtractMethod::JsonPointer`] did not point to a
+    /// string in the response body.
+    #[error("no string found at JSON pointer {0:?}")]
+    JsonPointer(String),
+    /// The response body was longer than the maximum length.
+    #[error("response body exceeds {0} bytes")]
+    BodyTooLarge(usize),
+    /// The URL could not be parsed.
+    #[error("invalid URL")]
+    Url(#[source] Box<dyn StdError + Send + Sync + 'static>),
+    /// A request header name or value was invalid.
+    #[error("invalid header")]
+    Header(#[source] Box<dyn StdError + Send + Sync + 'static>),
+    /// The proxy address or credentials were invalid.
+    #[error("invalid proxy")]
+    Proxy(#[source] Box<dyn StdError + Send + Sync + 'static>),
+    /// The response body could not be parsed.
+    #[error("failed to parse response body")]
+    Parse(#[source] Box<dyn StdError + Send + Sync + 'static>),
+    /// Other HTTP client error.
+    #[error("http client error")]
+    Other(#[source] Box<dyn StdError + Send + Sync + 'static>),
+}
+
+impl Error {
+    fn client(error: reqwest::Error) -> Self {
+        if error.is_timeout() {
+            return Self::Timeout;
+        }
+        if let Some(status) = error.status() {
+            return Self::Status(status.as_u16());
+        }
+        // TLS failures surface as connection errors, so they are checked first.
+        if find_source::<rustls::Error>(&error).is_some()
+            || find_source::<native_tls::Error>(&error).is_some()
+        {
+            return Self::Tls(error.into());
+        }
+        if error.is_connect() {
+            return match find_source::<io::Error>(&error) {
+                Some(io) if io.kind() == io::ErrorKind::ConnectionRefused => {
+                    Self::ConnectionRefused
+                }
+                _ => Self::Connect(error.into()),
+            };
+        }
+        if error.is_decode() {
+            return Self::Parse(error.into());
+        }
+        Self::Other(error.into())
+    }
+}
+
+/// Finds an error of type `T` in the source chain of `error`, including errors
+/// wrapped by an [`io::Error`].
+fn find_source<'e, T: StdError + 'static>(error: &'e (dyn StdError + 'static)) -> Option<&'e T> {
+    iter::successors(Some(error), |&error| {
+        // `io::Error` skips the error it wraps when returning its source.
+        match error
+            .downcast_ref::<io::Error>()
+            .and_then(io::Error::get_ref)
+        {
+            Some(inner) => Some(inner),
+            None => error.source(),
+        }
+    })
+    .find_map(|error| error.downcast_ref::<T>())
 }
 
 ///////////////////////////////////////////////////////////////////////////////
@@ -100,7 +276,10 @@ pub enum Error {
 pub struct Details {
     url: Url,
     server: Option<SocketAddr>,
+    proxy: Option<Url>,
     method: ExtractMethod,
+    key_values: Vec<(String, String)>,
+    metadata: Option<Metadata>,
 }
 
 impl Details {
@@ -115,13 +294,92 @@ impl Details {
     }
 
     /// The extract method used in the resolution of the associated IP address
-    pub fn extract_method(&self) -> ExtractMethod {
-        self.method
+    pub fn extract_method(&self) -> &ExtractMethod {
+        &self.method
+    }
+
+    /// The proxy the request was sent through, without its credentials.
+    ///
+    /// Only proxies set with [`Resolver::with_proxy`] are recorded, not those
+    /// taken from the environment or configured on a client set with
+    /// [`Resolver::with_client`].
+    #[must_use]
+    pub fn proxy(&self) -> Option<&Url> {
+        self.proxy.as_ref()
+    }
+
+    /// The value of a key in a body parsed with [`ExtractMethod::KeyValue`].
+    #[must_use]
+    pub fn key_value(&self, key: &str) -> Option<&str> {
+        self.key_values
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The location and network of our IP address, parsed from the response
+    /// of a resolver with a [`MetadataFormat`].
+    #[must_use]
+    pub fn metadata(&self) -> Option<&Metadata> {
+        self.metadata.as_ref()
+    }
+
+    /// Facts returned by a Cloudflare `/cdn-cgi/trace` endpoint, such as
+    /// [`HTTPS_CLOUDFLARE_TRACE`].
+    #[must_use]
+    pub fn cloudflare_trace(&self) -> Option<CloudflareTrace<'_>> {
+        if self.url.path() != "/cdn-cgi/trace" {
+            return None;
+        }
+        Some(CloudflareTrace {
+            colo: self.key_value("colo")?,
+            loc: self.key_value("loc")?,
+            warp: self.key_value("warp"),
+            tls: self.key_value("tls"),
+        })
+    }
+}
+
+/// Facts returned by a Cloudflare `/cdn-cgi/trace` endpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CloudflareTrace<'a> {
+    colo: &'a str,
+    loc: &'a str,
+    warp: Option<&'a str>,
+    tls: Option<&'a str>,
+}
+
+impl<'a> CloudflareTrace<'a> {
+    /// The IATA airport code of the Cloudflare data center that answered,
+    /// such as `AMS`.
+    #[must_use]
+    pub fn colo(&self) -> &'a str {
+        self.colo
+    }
+
+    /// The two-letter country code of our IP address, such as `NL`.
+    #[must_use]
+    pub fn loc(&self) -> &'a str {
+        self.loc
+    }
+
+    /// Whether the request came through Cloudflare WARP: `off`, `on` or
+    /// `plus`.
+    #[must_use]
+    pub fn warp(&self) -> Option<&'a str> {
+        self.warp
+    }
+
+    /// The TLS version of the connection, such as `TLSv1.3`, or `off` for
+    /// plain HTTP.
+    #[must_use]
+    pub fn tls(&self) -> Option<&'a str> {
+        self.tls
     }
 }
 
 /// Method used to extract an IP address from a http response
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub enum ExtractMethod {
     /// Parses the body with whitespace trimmed as the IP address.
     PlainText,
@@ -129,18 +387,739 @@ pub enum ExtractMethod {
     StripDoubleQuotes,
     /// Parses the value of the JSON property `"ip"` within the body as the IP address.
     ///
-    /// Note this method does not validate the JSON.
+    /// The body must be a valid JSON object with a string `"ip"` property.
     ExtractJsonIpField,
+    /// Parses the JSON string at a [JSON pointer] within the body as the IP
+    /// address, such as `/query` or `/data/ip`.
+    ///
+    /// The body must be valid JSON.
+    ///
+    /// [JSON pointer]: https://www.rfc-editor.org/rfc/rfc6901
+    JsonPointer(Cow<'static, str>),
+    /// Parses the first match of a regular expression within the body that is
+    /// an IP address, such as `Current IP Address: (?P<ip>[0-9.]+)`.
+    ///
+    /// The capture group named `ip` is parsed, or the whole match if the
+    /// expression has no such group.
+    ///
+    /// The expression is compiled once, when the [`Regex`] is created, so an
+    /// invalid expression is reported before any request is sent.
+    Regex(Regex),
+    /// Parses the first IPv4 or IPv6 address found anywhere within the body.
+    FindIp,
+    /// Parses the value of a key in a body of `key=value` lines as the IP
+    /// address, such as `ip` in a Cloudflare trace.
+    ///
+    /// The other keys are available from [`Details::key_value`].
+    KeyValue(Cow<'static, str>),
+    /// Parses the value of a response header as the IP address, ignoring the
+    /// body.
+    Header {
+        /// The name of the header, such as `X-Client-IP`.
+        name: Cow<'static, str>,
+        /// Whether the value is a comma-separated list, as in
+        /// `X-Forwarded-For`, in which case its first IP address is used.
+        list: bool,
+    },
+    /// Extracts the IP address with a user-supplied function.
+    ///
+    /// Responses with an unsuccessful status code are passed to the function
+    /// rather than rejected.
+    Custom(Extractor),
+}
+
+impl ExtractMethod {
+    /// The content type expected of responses, see
+    /// [`Resolver::with_content_type_check`].
+    fn content_type(&self) -> Option<&'static str> {
+        match self {
+            ExtractMethod::PlainText
+            | ExtractMethod::StripDoubleQuotes
+            | ExtractMethod::KeyValue(_) => Some("text/plain"),
+            ExtractMethod::ExtractJsonIpField | ExtractMethod::JsonPointer(_) => {
+                Some("application/json")
+            }
+            ExtractMethod::Regex(_)
+            | ExtractMethod::FindIp
+            | ExtractMethod::Header { .. }
+            | ExtractMethod::Custom(_) => None,
+        }
+    }
+}
+
+/// The location and network of an IP address, as reported by a provider.
+///
+/// Providers may omit any of these.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Metadata {
+    country: Option<String>,
+    region: Option<String>,
+    city: Option<String>,
+    asn: Option<u32>,
+    org: Option<String>,
+}
+
+impl Metadata {
+    /// The ISO 3166-1 alpha-2 code of the country, such as `NL`.
+    #[must_use]
+    pub fn country(&self) -> Option<&str> {
+        self.country.as_deref()
+    }
+
+    /// The name of the region within the country.
+    #[must_use]
+    pub fn region(&self) -> Option<&str> {
+        self.region.as_deref()
+    }
+
+    /// The name of the city.
+    #[must_use]
+    pub fn city(&self) -> Option<&str> {
+        self.city.as_deref()
+    }
+
+    /// The number of the autonomous system announcing the address.
+    #[must_use]
+    pub fn asn(&self) -> Option<u32> {
+        self.asn
+    }
+
+    /// The name of the organization the address belongs to.
+    #[must_use]
+    pub fn org(&self) -> Option<&str> {
+        self.org.as_deref()
+    }
+}
+
+/// The JSON format a provider returns [`Metadata`] in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum MetadataFormat {
+    /// The format of `https://ipinfo.io/json`.
+    IpInfo,
+    /// The format of `http://ip-api.com/json`.
+    IpApi,
+    /// The format of `https://ifconfig.co/json`.
+    IfconfigCo,
+}
+
+impl MetadataFormat {
+    fn parse(self, body: &[u8]) -> Option<Metadata> {
+        let json = serde_json::from_slice::<serde_json::Value>(body).ok()?;
+        let field = |key: &str| {
+            json.get(key)
+                .and_then(serde_json::Value::as_str)
+                .filter(|value| !value.is_empty())
+        };
+        let metadata = match self {
+            MetadataFormat::IpInfo => {
+                // The organization is prefixed with its AS number, such as
+                // `AS1136 KPN B.V.`.
+                let (asn, org) = field("org").map(parse_as_org).unwrap_or_default();
+                Metadata {
+                    country: field("country").map(str::to_owned),
+                    region: field("region").map(str::to_owned),
+                    city: field("city").map(str::to_owned),
+                    asn,
+                    org,
+                }
+            }
+            MetadataFormat::IpApi => Metadata {
+                country: field("countryCode").map(str::to_owned),
+                region: field("regionName").map(str::to_owned),
+                city: field("city").map(str::to_owned),
+                asn: field("as").and_then(|value| parse_as_org(value).0),
+                org: field("org").or_else(|| field("isp")).map(str::to_owned),
+            },
+            MetadataFormat::IfconfigCo => Metadata {
+                country: field("country_iso").map(str::to_owned),
+                region: field("region_name").map(str::to_owned),
+                city: field("city").map(str::to_owned),
+                asn: field("asn").and_then(|value| parse_as_org(value).0),
+                org: field("asn_org").map(str::to_owned),
+            },
+        };
+        Some(metadata)
+    }
+}
+
+/// Splits an `AS<number> <organization>` string, either part of which may be
+/// missing.
+fn parse_as_org(value: &str) -> (Option<u32>, Option<String>) {
+    let (number, org) = value.split_once(' ').unwrap_or((value, ""));
+    match number
+        .strip_prefix("AS")
+        .and_then(|number| number.parse().ok())
+    {
+        Some(asn) => (
+            Some(asn),
+            Some(org.trim())
+                .filter(|org| !org.is_empty())
+                .map(str::to_owned),
+        ),
+        None => (None, Some(value.to_owned())),
+    }
+}
+
+/// A response passed to an [`Extractor`].
+#[derive(Debug, Clone, Copy)]
+pub struct Response<'a> {
+    status: u16,
+    headers: &'a HeaderMap,
+    body: &'a [u8],
+}
+
+impl<'a> Response<'a> {
+    /// The HTTP status code of the response.
+    #[must_use]
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    /// The first value of a header, if it is valid UTF-8.
+    #[must_use]
+    pub fn header(&self, name: &str) -> Option<&'a str> {
+        self.headers.get(name)?.to_str().ok()
+    }
+
+    /// All headers of the response, as names and raw values.
+    pub fn headers(&self) -> impl Iterator<Item = (&'a str, &'a [u8])> {
+        self.headers
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_bytes()))
+    }
+
+    /// The body of the response.
+    ///
+    /// Empty for responses to `HEAD` requests.
+    #[must_use]
+    pub fn body(&self) -> &'a [u8] {
+        self.body
+    }
+}
+
+/// The function of an [`Extractor`].
+pub type ExtractFn = dyn Fn(&Response<'_>) -> Result<IpAddr, crate::Error> + Send + Sync;
+
+/// A user-supplied function extracting our IP address from a HTTP response.
+///
+/// ```
+/// use std::net::IpAddr;
+///
+/// use public_ip::http::{ExtractMethod, Extractor};
+///
+/// // Extracts the address from a body of the form `addr: 1.2.3.4`, or an
+/// // `X-Addr` header when the server is overloaded.
+/// const METHOD: ExtractMethod = ExtractMethod::Custom(Extractor::new_static(&|response| {
+///     if response.status() == 503 {
+///         return Ok(response.header("X-Addr").ok_or(public_ip::Error::Addr)?.parse()?);
+///     }
+///     let body = std::str::from_utf8(response.body())?;
+///     Ok(body.trim().strip_prefix("addr: ").ok_or(public_ip::Error::Addr)?.parse::<IpAddr>()?)
+/// }));
+/// ```
+pub type Extractor = crate::Extractor<ExtractFn>;
+
+impl Extractor {
+    /// Create a new extractor from a function.
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(&Response<'_>) -> Result<IpAddr, crate::Error> + Send + Sync + 'static,
+    {
+        Self::shared(Arc::new(f))
+    }
+
+    fn extract(&self, response: &Response<'_>) -> Result<IpAddr, crate::Error> {
+        self.get()(response)
+    }
+}
+
+/// A proxy to send HTTP requests through.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Proxy<'r> {
+    /// The proxies configured by the `HTTP_PROXY`, `HTTPS_PROXY`, `ALL_PROXY`
+    /// and `NO_PROXY` environment variables, and on macOS the system
+    /// configuration.
+    ///
+    /// This is the default.
+    Env,
+    /// Connect to servers directly, ignoring any proxy configuration.
+    Direct,
+    /// An HTTP proxy at `host:port`, tunneling HTTPS requests with `CONNECT`.
+    Http(Cow<'r, str>),
+    /// A SOCKS5 proxy at `host:port`.
+    ///
+    /// Hostnames are resolved by the proxy, so DNS queries do not leave the
+    /// local network either.
+    Socks5(Cow<'r, str>),
+}
+
+impl Proxy<'_> {
+    fn url(&self, auth: Option<(&str, &str)>) -> Result<Option<Url>, Error> {
+        let (scheme, addr) = match self {
+            Proxy::Env | Proxy::Direct => return Ok(None),
+            Proxy::Http(addr) => ("http", addr),
+            Proxy::Socks5(addr) => ("socks5h", addr),
+        };
+        let mut url =
+            Url::parse(&format!("{scheme}://{addr}")).map_err(|err| Error::Proxy(err.into()))?;
+        if let Some((username, password)) = auth {
+            // Only fails for URLs without a host, which do not parse.
+            let _ = url.set_username(username);
+            let _ = url.set_password(Some(password));
+        }
+        Ok(Some(url))
+    }
+}
+
+/// A client-side limit on the rate of requests to a provider.
+///
+/// Requests are limited with a token bucket holding up to `requests` tokens,
+/// refilled at a rate of `requests` per `per`. The bucket is shared by all
+/// resolvers in the process with the same limit and URL origin, so requests to
+/// a provider stay under its limit however many resolvers are used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RateLimit {
+    requests: u32,
+    per: Duration,
+}
+
+impl RateLimit {
+    /// Create a new limit of `requests` requests per `per`.
+    #[must_use]
+    pub const fn new(requests: u32, per: Duration) -> Self {
+        Self { requests, per }
+    }
+
+    fn acquire(self, url: &Url) -> Result<(), Error> {
+        let mut buckets = BUCKETS
+            .get_or_init(Mutex::default)
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        buckets
+            .entry((url.origin().ascii_serialization(), self))
+            .or_insert_with(|| Bucket::new(self, now))
+            .take(self, now)
+            .map_err(|retry_after| Error::RateLimited {
+                retry_after: Some(retry_after),
+            })
+    }
+}
+
+/// The tokens left for requests under a [`RateLimit`].
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(limit: RateLimit, now: Instant) -> Self {
+        Self {
+            tokens: f64::from(limit.requests),
+            updated: now,
+        }
+    }
+
+    /// Takes a token for a request, or returns how long until one is
+    /// available.
+    fn take(&mut self, limit: RateLimit, now: Instant) -> Result<(), Duration> {
+        let rate = f64::from(limit.requests) / limit.per.as_secs_f64();
+        self.tokens = f64::min(
+            self.tokens + now.duration_since(self.updated).as_secs_f64() * rate,
+            f64::from(limit.requests),
+        );
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        if rate > 0.0 {
+            // Very long periods overflow a `Duration`.
+            Err(Duration::try_from_secs_f64((1.0 - self.tokens) / rate).unwrap_or(Duration::MAX))
+        } else {
+            Err(limit.per)
+        }
+    }
+}
+
+/// Buckets shared by resolvers with the same rate limit, per URL origin.
+static BUCKETS: OnceLock<Mutex<HashMap<(String, RateLimit), Bucket>>> = OnceLock::new();
+
+/// TLS settings for the connections of a [`Resolver`].
+///
+/// By default servers are verified against the root certificates of the
+/// platform. The settings can trust further root certificates, such as the CA
+/// of a self-hosted service, pin the public keys a provider's certificates
+/// must chain to, and present a client certificate.
+#[derive(Debug, Clone)]
+pub struct Tls {
+    native_roots: bool,
+    roots: Vec<CertificateDer<'static>>,
+    pins: Vec<[u8; 32]>,
+    identity: Option<Arc<Identity>>,
+    /// Clients built with these settings, shared by clones of them.
+    clients: Pool,
+}
+
+impl Tls {
+    /// Create new TLS settings, trusting the root certificates of the platform.
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            native_roots: true,
+            roots: Vec::new(),
+            pins: Vec::new(),
+            identity: None,
+            clients: Pool::new(),
+        }
+    }
+
+    /// Whether to trust the root certificates of the platform.
+    ///
+    /// Enabled by default. When disabled, only the root certificates added with
+    /// [`Tls::with_root_certificates_pem`] are trusted.
+    #[must_use]
+    pub fn with_native_roots(mut self, enabled: bool) -> Self {
+        self.native_roots = enabled;
+        self.clients = Pool::new();
+        self
+    }
+
+    /// Trust the root certificates of a PEM bundle.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the bundle cannot be parsed or holds no certificate.
+    pub fn with_root_certificates_pem(mut self, pem: &[u8]) -> Result<Self, Error> {
+        let len = self.roots.len();
+        for cert in CertificateDer::pem_slice_iter(pem) {
+            self.roots
+                .push(cert.map_err(|err| Error::TlsConfig(err.into()))?);
+        }
+        if self.roots.len() == len {
+            return Err(Error::TlsConfig("no certificate found".into()));
+        }
+        self.clients = Pool::new();
+        Ok(self)
+    }
+
+    /// Require the certificate chain of the server to include a public key.
+    ///
+    /// `sha256` is the SHA-256 digest of the DER encoded subject public key
+    /// info, of the server's certificate, an intermediate or the root. With
+    /// several pins, any of them may match. The chain is still verified
+    /// against the trusted root certificates. The pin of the certificate in
+    /// `cert.pem` can be computed with:
+    ///
+    /// ```text
+    /// openssl x509 -in cert.pem -noout -pubkey \
+    ///     | openssl pkey -pubin -outform der \
+    ///     | openssl dgst -sha256 -binary
+    /// ```
+    #[must_use]
+    pub fn with_pinned_key(mut self, sha256: [u8; 32]) -> Self {
+        self.pins.push(sha256);
+        self.clients = Pool::new();
+        self
+    }
+
+    /// Present a client certificate to servers that request one.
+    ///
+    /// The PEM must hold the certificate, followed by any intermediate
+    /// certificates, and its private key.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the PEM cannot be parsed or lacks the certificate or key.
+    pub fn with_identity_pem(mut self, pem: &[u8]) -> Result<Self, Error> {
+        let chain = CertificateDer::pem_slice_iter(pem)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| Error::TlsConfig(err.into()))?;
+        if chain.is_empty() {
+            return Err(Error::TlsConfig("no certificate found".into()));
+        }
+        let key = PrivateKeyDer::from_pem_slice(pem).map_err(|err| Error::TlsConfig(err.into()))?;
+        self.identity = Some(Arc::new(Identity { chain, key }));
+        self.clients = Pool::new();
+        Ok(self)
+    }
+
+    fn client_config(&self) -> Result<rustls::ClientConfig, Error> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let verifier = self.verifier(&provider)?;
+        let builder = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(|err| Error::TlsConfig(err.into()))?
+            // The verifier is WebPKI's, wrapped to check pins when there are
+            // any.
+            .dangerous()
+            .with_custom_certificate_verifier(verifier);
+        let mut config = match &self.identity {
+            Some(identity) => builder
+                .with_client_auth_cert(identity.chain.clone(), identity.key.clone_key())
+                .map_err(|err| Error::TlsConfig(err.into()))?,
+            None => builder.with_no_client_auth(),
+        };
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(config)
+    }
+
+    fn verifier(
+        &self,
+        provider: &Arc<CryptoProvider>,
+    ) -> Result<Arc<dyn ServerCertVerifier>, Error> {
+        let mut roots = RootCertStore::empty();
+        if self.native_roots {
+            roots.add_parsable_certificates(native_roots().iter().cloned());
+        }
+        for cert in &self.roots {
+            roots
+                .add(cert.clone())
+                .map_err(|err| Error::TlsConfig(err.into()))?;
+        }
+        let roots = Arc::new(roots);
+        let verifier = WebPkiServerVerifier::builder_with_provider(roots.clone(), provider.clone())
+            .build()
+            .map_err(|err| Error::TlsConfig(err.into()))?;
+        if self.pins.is_empty() {
+            return Ok(verifier);
+        }
+        Ok(Arc::new(PinnedVerifier {
+            inner: verifier,
+            roots,
+            algorithms: provider.signature_verification_algorithms,
+            pins: self.pins.clone(),
+        }))
+    }
+}
+
+impl Default for Tls {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A client certificate chain and its private key.
+struct Identity {
+    chain: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+}
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Identity")
+            .field("chain", &self.chain)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The root certificates of the platform, loaded once.
+fn native_roots() -> &'static [CertificateDer<'static>] {
+    static ROOTS: OnceLock<Vec<CertificateDer<'static>>> = OnceLock::new();
+    ROOTS.get_or_init(|| rustls_native_certs::load_native_certs().certs)
+}
+
+/// Verifies servers as usual, then requires a path from the server's
+/// certificate to a trusted root that includes a pinned key.
+#[derive(Debug)]
+struct PinnedVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    roots: Arc<RootCertStore>,
+    algorithms: WebPkiSupportedAlgorithms,
+    pins: Vec<[u8; 32]>,
+}
+
+impl PinnedVerifier {
+    fn is_pinned(&self, spki: &[u8]) -> bool {
+        let digest = digest::digest(&digest::SHA256, spki);
+        self.pins.iter().any(|pin| pin[..] == *digest.as_ref())
+    }
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+        let unpinned =
+            || rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure);
+        // The server may send more certificates than the path uses, so the
+        // pins are checked against the paths built from them.
+        let is_pinned = |path: &webpki::VerifiedPath<'_>| {
+            let anchor = der_sequence(&path.anchor().subject_public_key_info);
+            let pinned = self.is_pinned(&anchor)
+                || self.is_pinned(&path.end_entity().subject_public_key_info())
+                || path
+                    .intermediate_certificates()
+                    .any(|cert| self.is_pinned(&cert.subject_public_key_info()));
+            if pinned {
+                Ok(())
+            } else {
+                Err(webpki::Error::UnknownIssuer)
+            }
+        };
+        webpki::EndEntityCert::try_from(end_entity)
+            .map_err(|_| unpinned())?
+            .verify_for_usage(
+                self.algorithms.all,
+                &self.roots.roots,
+                intermediates,
+                now,
+                webpki::KeyUsage::server_auth(),
+                None,
+                Some(&is_pinned),
+            )
+            .map_err(|_| unpinned())?;
+        Ok(verified)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// Wraps DER encoded contents in a `SEQUENCE`.
+fn der_sequence(contents: &[u8]) -> Vec<u8> {
+    let mut der = vec![0x30];
+    match u8::try_from(contents.len()) {
+        Ok(len) if len < 0x80 => der.push(len),
+        _ => {
+            let len = contents.len().to_be_bytes();
+            let len = &len[len.iter().take_while(|&&byte| byte == 0).count()..];
+            der.extend(u8::try_from(len.len()).map(|count| 0x80 | count));
+            der.extend_from_slice(len);
+        }
+    }
+    der.extend_from_slice(contents);
+    der
+}
+
+/// Options applied to every request sent by a [`Resolver`].
+#[derive(Debug, Clone, Copy)]
+struct RequestOptions {
+    head: bool,
+    content_type_check: bool,
+    max_body_len: usize,
+    metadata: Option<MetadataFormat>,
+    rate_limit: Option<RateLimit>,
+}
+
+impl RequestOptions {
+    const fn new() -> Self {
+        Self {
+            head: false,
+            content_type_check: false,
+            max_body_len: MAX_BODY_LEN,
+            metadata: None,
+            rate_limit: None,
+        }
+    }
 }
 
 ///////////////////////////////////////////////////////////////////////////////
 // Resolver
 
+/// Name and value pairs, such as headers or query parameters.
+pub type Pairs<'r> = Cow<'r, [(Cow<'r, str>, Cow<'r, str>)]>;
+
 /// Options to build a HTTP resolver
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct Resolver<'r> {
     url: Cow<'r, str>,
     method: ExtractMethod,
+    options: RequestOptions,
+    headers: Pairs<'r>,
+    user_agent: Option<Cow<'r, str>>,
+    query: Pairs<'r>,
+    proxy: Proxy<'r>,
+    proxy_auth: Option<(Cow<'r, str>, Cow<'r, str>)>,
+    tls: Option<Tls>,
+    client: Option<reqwest::Client>,
+    pool: Option<Pool>,
+}
+
+impl fmt::Debug for Resolver<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Header values and query parameters may carry credentials such as
+        // API tokens, so only their names are shown, and the proxy password is
+        // left out.
+        f.debug_struct("Resolver")
+            .field("url", &without_query(&self.url))
+            .field("method", &self.method)
+            .field("options", &self.options)
+            .field("headers", &RedactedValues(&self.headers))
+            .field("user_agent", &self.user_agent)
+            .field("query", &RedactedValues(&self.query))
+            .field("proxy", &self.proxy)
+            .field(
+                "proxy_auth",
+                &self
+                    .proxy_auth
+                    .as_ref()
+                    .map(|(username, _)| (username, Redacted)),
+            )
+            .field("tls", &self.tls)
+            .field("client", &self.client)
+            .field("pool", &self.pool)
+            .finish()
+    }
+}
+
+/// Shows the names of pairs without their values.
+struct RedactedValues<'a, 'r>(&'a [(Cow<'r, str>, Cow<'r, str>)]);
+
+impl fmt::Debug for RedactedValues<'_, '_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(self.0.iter().map(|(name, _)| (name, Redacted)))
+            .finish()
+    }
+}
+
+/// Stands in for a value that is not shown.
+struct Redacted;
+
+impl fmt::Debug for Redacted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+/// The URL without its query string, which may carry credentials.
+fn without_query(url: &str) -> &str {
+    url.split_once('?').map_or(url, |(url, _)| url)
 }
 
 impl<'r> Resolver<'r> {
@@ -152,7 +1131,202 @@ impl<'r> Resolver<'r> {
         Self {
             url: url.into(),
             method,
+            options: RequestOptions::new(),
+            headers: Cow::Borrowed(&[]),
+            user_agent: None,
+            query: Cow::Borrowed(&[]),
+            proxy: Proxy::Env,
+            proxy_auth: None,
+            tls: None,
+            client: None,
+            pool: None,
+        }
+    }
+
+    /// Send requests with the given client.
+    ///
+    /// The client is used as configured, so its proxies, root certificates
+    /// and timeouts apply to every request. Unlike the default client it is
+    /// not bound to the requested IP version, so resolutions may fail with
+    /// [`crate::Error::Version`] unless the client is configured accordingly
+    /// (see [`reqwest::ClientBuilder::local_address`]).
+    ///
+    /// Without a client, a client per IP version is taken from the
+    /// [`Pool`] set with [`Resolver::with_pool`], or created for every
+    /// resolution.
+    #[must_use]
+    pub fn with_client(mut self, client: reqwest::Client) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Connect to servers with the given TLS settings.
+    ///
+    /// Resolvers with TLS settings do not take clients from a [`Pool`], but
+    /// reuse the clients kept with the settings, which are shared by clones
+    /// of them. Like a pool, the settings should then not be shared between
+    /// Tokio runtimes. The settings are ignored if a client is set with
+    /// [`Resolver::with_client`], which is then used as configured.
+    #[must_use]
+    pub fn with_tls(mut self, tls: Tls) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Set a [`Pool`] of clients to reuse across resolutions.
+    ///
+    /// Without a pool, a new client is created for every resolution.
+    #[must_use]
+    pub fn with_pool(mut self, pool: Pool) -> Self {
+        self.pool = Some(pool);
+        self
+    }
+
+    /// Send `HEAD` requests rather than `GET` requests.
+    ///
+    /// Responses to `HEAD` requests have no body, so this only suits
+    /// [`ExtractMethod::Header`]. Disabled by default.
+    #[must_use]
+    pub const fn with_head(mut self, head: bool) -> Self {
+        self.options.head = head;
+        self
+    }
+
+    /// Reject responses whose content type does not match the extract method.
+    ///
+    /// [`ExtractMethod::PlainText`] and [`ExtractMethod::StripDoubleQuotes`]
+    /// expect `text/plain`, and the JSON methods expect `application/json`.
+    /// Other methods accept any content type. This catches captive portals and
+    /// error pages served with a successful status. Disabled by default.
+    #[must_use]
+    pub const fn with_content_type_check(mut self, content_type_check: bool) -> Self {
+        self.options.content_type_check = content_type_check;
+        self
+    }
+
+    /// Set the maximum length of a response body, in bytes.
+    ///
+    /// Longer responses are rejected without reading them further. Defaults
+    /// to 64 KiB.
+    #[must_use]
+    pub const fn with_max_body_len(mut self, max_body_len: usize) -> Self {
+        self.options.max_body_len = max_body_len;
+        self
+    }
+
+    /// Parse the [`Metadata`] of our IP address from responses in the given
+    /// format, see [`Details::metadata`].
+    ///
+    /// Responses that are not in the format have no metadata, but are
+    /// otherwise resolved as usual.
+    #[must_use]
+    pub const fn with_metadata(mut self, format: MetadataFormat) -> Self {
+        self.options.metadata = Some(format);
+        self
+    }
+
+    /// Limit the rate of requests to the provider.
+    ///
+    /// Requests over the limit fail with [`Error::RateLimited`] without being
+    /// sent.
+    #[must_use]
+    pub const fn with_rate_limit(mut self, rate_limit: RateLimit) -> Self {
+        self.options.rate_limit = Some(rate_limit);
+        self
+    }
+
+    /// Send the given headers with every request, such as an API token.
+    ///
+    /// Headers replace any of the same name set on the client.
+    ///
+    /// ```
+    /// use public_ip::http::{ExtractMethod, Resolver};
+    ///
+    /// let token = std::env::var("IPINFO_TOKEN").unwrap_or_default();
+    /// let resolver = Resolver::new("https://ipinfo.io/ip", ExtractMethod::PlainText)
+    ///     .with_headers(vec![("Authorization".into(), format!("Bearer {token}").into())]);
+    /// ```
+    #[must_use]
+    pub fn with_headers<H>(mut self, headers: H) -> Self
+    where
+        H: Into<Pairs<'r>>,
+    {
+        self.headers = headers.into();
+        self
+    }
+
+    /// Set the `User-Agent` header sent with every request.
+    ///
+    /// Takes precedence over a `User-Agent` set with
+    /// [`Resolver::with_headers`].
+    #[must_use]
+    pub fn with_user_agent<U>(mut self, user_agent: U) -> Self
+    where
+        U: Into<Cow<'r, str>>,
+    {
+        self.user_agent = Some(user_agent.into());
+        self
+    }
+
+    /// Append the given query parameters to the URL of every request.
+    #[must_use]
+    pub fn with_query<Q>(mut self, query: Q) -> Self
+    where
+        Q: Into<Pairs<'r>>,
+    {
+        self.query = query.into();
+        self
+    }
+
+    /// Send requests through the given proxy.
+    ///
+    /// Our IP address is then the egress address of the proxy. Ignored when a
+    /// client is set with [`Resolver::with_client`]. Defaults to
+    /// [`Proxy::Env`].
+    #[must_use]
+    pub fn with_proxy(mut self, proxy: Proxy<'r>) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
+    /// Authenticate with the proxy using the given username and password.
+    #[must_use]
+    pub fn with_proxy_auth<U, P>(mut self, username: U, password: P) -> Self
+    where
+        U: Into<Cow<'r, str>>,
+        P: Into<Cow<'r, str>>,
+    {
+        self.proxy_auth = Some((username.into(), password.into()));
+        self
+    }
+
+    fn client(&self, version: Version) -> Result<(reqwest::Client, Option<Url>), crate::Error> {
+        if let Some(client) = &self.client {
+            return Ok((client.clone(), None));
         }
+        let auth = self
+            .proxy_auth
+            .as_ref()
+            .map(|(username, password)| (username.as_ref(), password.as_ref()));
+        let proxy = self.proxy.url(auth)?;
+        let route = match &proxy {
+            Some(url) => Route::Proxy(url.clone()),
+            None if self.proxy == Proxy::Direct => Route::Direct,
+            None => Route::Env,
+        };
+        let client = match (&self.tls, &self.pool) {
+            (Some(tls), _) => tls.clients.client(version, route, Some(tls))?,
+            (None, Some(pool)) => pool.client(version, route, None)?,
+            (None, None) => client_builder(version, &route)?
+                .build()
+                .map_err(Error::client)?,
+        };
+        let proxy = proxy.map(|mut url| {
+            let _ = url.set_username("");
+            let _ = url.set_password(None);
+            url
+        });
+        Ok((client, proxy))
     }
 }
 
@@ -163,6 +1337,15 @@ impl Resolver<'static> {
         Self {
             url: Cow::Borrowed(url),
             method,
+            options: RequestOptions::new(),
+            headers: Cow::Borrowed(&[]),
+            user_agent: None,
+            query: Cow::Borrowed(&[]),
+            proxy: Proxy::Env,
+            proxy_auth: None,
+            tls: None,
+            client: None,
+            pool: None,
         }
     }
 }
@@ -201,48 +1384,861 @@ struct JsonIp {
     ip: String,
 }
 
-async fn resolve(
-    version: Version,
-    url: Url,
-    method: ExtractMethod,
-) -> Result<(IpAddr, crate::Details), crate::Error> {
+/// How a client reaches servers.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Route {
+    Env,
+    Direct,
+    Proxy(Url),
+}
+
+fn client_builder(version: Version, route: &Route) -> Result<reqwest::ClientBuilder, crate::Error> {
     let mut client_builder = reqwest::Client::builder();
     client_builder = match version {
         Version::V4 => client_builder.local_address(Some("0.0.0.0".parse()?)),
         Version::V6 => client_builder.local_address(Some("[::]:0".parse()?)),
         Version::Any => client_builder,
     };
-    let client = client_builder.build()?;
-    let response = client.get(url.clone()).send().await?;
+    client_builder = match route {
+        Route::Env => client_builder,
+        Route::Direct => client_builder.no_proxy(),
+        Route::Proxy(url) => client_builder
+            .proxy(reqwest::Proxy::all(url.clone()).map_err(|err| Error::Proxy(err.into()))?),
+    };
+    Ok(client_builder)
+}
+
+async fn resolve(
+    url: Url,
+    method: ExtractMethod,
+    options: RequestOptions,
+    headers: HeaderMap,
+    client: reqwest::Client,
+    proxy: Option<Url>,
+) -> Result<(IpAddr, crate::Details), crate::Error> {
+    let request = if options.head {
+        client.head(url.clone())
+    } else {
+        client.get(url.clone())
+    };
+    if let Some(limit) = options.rate_limit {
+        limit.acquire(&url)?;
+    }
+    let response = request
+        .headers(headers)
+        .send()
+        .await
+        .map_err(Error::client)?;
     // TODO
     let server = response.remote_addr();
-    let address_str = match method {
-        ExtractMethod::PlainText => response.text().await?.trim().to_owned(),
-        ExtractMethod::ExtractJsonIpField => response.json::<JsonIp>().await?.ip,
-        ExtractMethod::StripDoubleQuotes => {
-            response.text().await?.trim().trim_matches('"').to_owned()
+    let status = response.status();
+    if !status.is_success() && !matches!(method, ExtractMethod::Custom(_)) {
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|value| parse_retry_after(value.to_str().ok()?, SystemTime::now()));
+        return Err(match status {
+            StatusCode::TOO_MANY_REQUESTS => Error::RateLimited { retry_after },
+            StatusCode::SERVICE_UNAVAILABLE if retry_after.is_some() => {
+                Error::RateLimited { retry_after }
+            }
+            status => Error::Status(status.as_u16()),
+        }
+        .into());
+    }
+    if options.content_type_check {
+        check_content_type(response.headers(), &method)?;
+    }
+    let mut key_values = Vec::new();
+    let mut metadata = None;
+    let address = match &method {
+        ExtractMethod::Header { name, list } => parse_header(response.headers(), name, *list)?,
+        ExtractMethod::Custom(extractor) => {
+            let headers = response.headers().clone();
+            let body = read_body(response, options.max_body_len).await?;
+            metadata = options.metadata.and_then(|format| format.parse(&body));
+            extractor.extract(&Response {
+                status: status.as_u16(),
+                headers: &headers,
+                body: &body,
+            })?
+        }
+        method => {
+            let body = read_body(response, options.max_body_len).await?;
+            metadata = options.metadata.and_then(|format| format.parse(&body));
+            if let ExtractMethod::KeyValue(_) = method {
+                key_values = parse_key_values(&body)
+                    .map(|(key, value)| (key.to_owned(), value.to_owned()))
+                    .collect();
+            }
+            parse_body(&body, method)?
         }
     };
-    let address = address_str.parse()?;
     let details = Box::new(Details {
         url,
         server,
+        proxy,
         method,
+        key_values,
+        metadata,
     });
     Ok((address, crate::Details::from(details)))
 }
 
+fn parse_body(body: &[u8], method: &ExtractMethod) -> Result<IpAddr, crate::Error> {
+    let address = match method {
+        ExtractMethod::PlainText => str::from_utf8(body)?.trim().parse()?,
+        ExtractMethod::ExtractJsonIpField => serde_json::from_slice::<JsonIp>(body)
+            .map_err(|err| Error::Parse(err.into()))?
+            .ip
+            .parse()?,
+        ExtractMethod::StripDoubleQuotes => {
+            str::from_utf8(body)?.trim().trim_matches('"').parse()?
+        }
+        ExtractMethod::JsonPointer(pointer) => {
+            let json = serde_json::from_slice::<serde_json::Value>(body)
+                .map_err(|err| Error::Parse(err.into()))?;
+            json.pointer(pointer)
+                .and_then(serde_json::Value::as_str)
+                .ok_or_else(|| Error::JsonPointer(pointer.clone().into_owned()))?
+                .parse()?
+        }
+        ExtractMethod::Regex(regex) => {
+            let body = String::from_utf8_lossy(body);
+            let address = regex
+                .captures_iter(&body)
+                .filter_map(|captures| captures.name("ip").or_else(|| captures.get(0)))
+                .find_map(|capture| capture.as_str().trim().parse().ok());
+            address.ok_or(crate::Error::Addr)?
+        }
+        ExtractMethod::FindIp => {
+            find_ip(&String::from_utf8_lossy(body)).ok_or(crate::Error::Addr)?
+        }
+        ExtractMethod::KeyValue(key) => parse_key_values(body)
+            .find(|(k, _)| k == key)
+            .ok_or(crate::Error::Addr)?
+            .1
+            .parse()?,
+        ExtractMethod::Header { .. } | ExtractMethod::Custom(_) => {
+            unreachable!("methods using more than the body are handled before parsing it")
+        }
+    };
+    Ok(address)
+}
+
+/// Parses a `Retry-After` header, which is either a number of seconds or a
+/// date.
+fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    // A date in the past means we may retry right away.
+    Some(date.duration_since(now).unwrap_or_default())
+}
+
+fn parse_key_values(body: &[u8]) -> impl Iterator<Item = (&str, &str)> {
+    str::from_utf8(body)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.trim(), value.trim()))
+}
+
+fn parse_header(headers: &HeaderMap, name: &str, list: bool) -> Result<IpAddr, crate::Error> {
+    let mut values = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok());
+    if list {
+        values
+            .flat_map(|value| value.split(','))
+            .find_map(|value| value.trim().parse().ok())
+            .ok_or(crate::Error::Addr)
+    } else {
+        Ok(values.next().ok_or(crate::Error::Addr)?.trim().parse()?)
+    }
+}
+
+/// Finds the first IP address in free-form text.
+fn find_ip(text: &str) -> Option<IpAddr> {
+    text.split(|c: char| !(c.is_ascii_hexdigit() || c == '.' || c == ':'))
+        // Words such as `Bad::Cafe` or the `::bef` of a CSS `::before` parse
+        // as IPv6 addresses, but addresses written out have digits.
+        .filter(|token| token.contains(|c: char| c.is_ascii_digit()))
+        .filter_map(|token| {
+            // Tokens may be joined to surrounding punctuation, such as the
+            // colon of `Address:1.2.3.4` or a full stop ending a sentence.
+            token
+                .parse()
+                .or_else(|_| token.trim_matches(|c| c == '.' || c == ':').parse())
+                .ok()
+        })
+        .find(|addr| !is_local(*addr))
+}
+
+/// Whether an address can only be local, and so cannot be our public IP
+/// address.
+fn is_local(addr: IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(addr) => addr.is_unspecified() || addr.is_loopback() || addr.is_link_local(),
+        IpAddr::V6(addr) => {
+            addr.is_unspecified() || addr.is_loopback() || addr.segments()[0] & 0xffc0 == 0xfe80
+        }
+    }
+}
+
+fn request_headers(
+    headers: &[(Cow<'_, str>, Cow<'_, str>)],
+    user_agent: Option<&str>,
+) -> Result<HeaderMap, Error> {
+    let mut map = HeaderMap::with_capacity(headers.len() + 1);
+    for (name, value) in headers {
+        map.append(
+            HeaderName::from_bytes(name.as_bytes()).map_err(|err| Error::Header(err.into()))?,
+            HeaderValue::from_str(value).map_err(|err| Error::Header(err.into()))?,
+        );
+    }
+    if let Some(user_agent) = user_agent {
+        map.insert(
+            USER_AGENT,
+            HeaderValue::from_str(user_agent).map_err(|err| Error::Header(err.into()))?,
+        );
+    }
+    Ok(map)
+}
+
+fn check_content_type(headers: &HeaderMap, method: &ExtractMethod) -> Result<(), Error> {
+    let Some(expected) = method.content_type() else {
+        return Ok(());
+    };
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    // Parameters such as `charset` are ignored.
+    let essence = content_type.and_then(|value| value.split(';').next());
+    match essence {
+        Some(essence) if essence.trim().eq_ignore_ascii_case(expected) => Ok(()),
+        _ => Err(Error::ContentType(content_type.map(str::to_owned))),
+    }
+}
+
+async fn read_body(mut response: reqwest::Response, max_len: usize) -> Result<Vec<u8>, Error> {
+    let too_large = |len| len > max_len;
+    if let Some(len) = response.content_length() {
+        if usize::try_from(len).map_or(true, too_large) {
+            return Err(Error::BodyTooLarge(max_len));
+        }
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(Error::client)? {
+        if too_large(body.len() + chunk.len()) {
+            return Err(Error::BodyTooLarge(max_len));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
 impl<'r> crate::Resolver<'r> for Resolver<'r> {
     fn resolve(&self, version: Version) -> Resolutions<'r> {
-        let method = self.method;
-        let url: Url = match self.url.as_ref().parse() {
+        let method = self.method.clone();
+        let mut url: Url = match self.url.as_ref().parse() {
             Ok(name) => name,
-            Err(err) => return Box::pin(stream::once(future::ready(Err(crate::Error::new(err))))),
+            Err(err) => {
+                let err = Error::Url(Box::new(err));
+                return Box::pin(stream::once(future::ready(Err(err.into()))));
+            }
+        };
+        if !self.query.is_empty() {
+            url.query_pairs_mut().extend_pairs(self.query.iter());
+        }
+        let headers = match request_headers(&self.headers, self.user_agent.as_deref()) {
+            Ok(headers) => headers,
+            Err(err) => return Box::pin(stream::once(future::ready(Err(err.into())))),
+        };
+        let (client, proxy) = match self.client(version) {
+            Ok(client) => client,
+            Err(err) => return Box::pin(stream::once(future::ready(Err(err)))),
         };
-        let span = trace_span!("http resolver", ?version, ?method, %url);
+        let span = trace_span!(
+            "http resolver",
+            ?version,
+            ?method,
+            url = without_query(url.as_str())
+        );
         let resolutions = HttpResolutions::HttpRequest {
-            response: Box::pin(resolve(version, url, method)),
+            response: Box::pin(resolve(url, method, self.options, headers, client, proxy)),
         };
         Box::pin(resolutions.instrument(span))
     }
 }
+
+///////////////////////////////////////////////////////////////////////////////
+// Pool
+
+/// A pool of HTTP clients shared between resolutions.
+///
+/// Clients are kept per IP version and proxy, so resolvers sharing a pool
+/// reuse connections rather than creating a new client for every request.
+///
+/// The connections of a client belong to the Tokio runtime they were opened
+/// on and stop working once it shuts down, so a pool should not outlive the
+/// runtime it is used on nor be shared with another one.
+///
+/// Cloning a pool is cheap and the clone shares the same clients.
+#[derive(Clone, Default)]
+pub struct Pool {
+    clients: Arc<Mutex<HashMap<(Version, Route), reqwest::Client>>>,
+}
+
+impl Pool {
+    /// Create a new empty pool.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn client(
+        &self,
+        version: Version,
+        route: Route,
+        tls: Option<&Tls>,
+    ) -> Result<reqwest::Client, crate::Error> {
+        let mut clients = self.lock();
+        let key = (version, route);
+        if let Some(client) = clients.get(&key) {
+            return Ok(client.clone());
+        }
+        let mut builder = client_builder(version, &key.1)?;
+        if let Some(tls) = tls {
+            builder = builder.use_preconfigured_tls(tls.client_config()?);
+        }
+        let client = builder.build().map_err(Error::client)?;
+        clients.insert(key, client.clone());
+        Ok(client)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<(Version, Route), reqwest::Client>> {
+        self.clients.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl fmt::Debug for Pool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Proxy URLs may carry credentials, so only the number of clients is
+        // shown.
+        f.debug_struct("Pool")
+            .field("clients", &self.lock().len())
+            .finish()
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_ips() {
+        let dyndns = "<html><head><title>Current IP Check</title></head>\
+            <body>Current IP Address: 192.0.2.1</body></html>\r\n";
+        assert_eq!(find_ip(dyndns), Some("192.0.2.1".parse().unwrap()));
+        let page = "<style>a::before { content: '::' }</style>\
+            <p>Bad::Cafe, your address is 2001:db8::1.</p>";
+        assert_eq!(find_ip(page), Some("2001:db8::1".parse().unwrap()));
+        let page = "<style>p::after, a::before { color: red }</style>";
+        assert_eq!(find_ip(page), None);
+
+        // Local addresses cannot be our public IP address.
+        let text = "0.0.0.0 127.0.0.1 169.254.1.1 :: ::1 fe80::1 fe9f::1 198.51.100.7";
+        assert_eq!(find_ip(text), Some("198.51.100.7".parse().unwrap()));
+        assert_eq!(find_ip("server fe80::1%eth0, ::1"), None);
+    }
+
+    #[test]
+    fn metadata() {
+        let ipinfo = br#"{
+            "ip": "192.0.2.1",
+            "city": "Amsterdam",
+            "region": "North Holland",
+            "country": "NL",
+            "loc": "52.3740,4.8897",
+            "org": "AS1136 KPN B.V.",
+            "postal": "1012",
+            "timezone": "Europe/Amsterdam"
+        }"#;
+        assert_eq!(
+            MetadataFormat::IpInfo.parse(ipinfo),
+            Some(Metadata {
+                country: Some("NL".to_owned()),
+                region: Some("North Holland".to_owned()),
+                city: Some("Amsterdam".to_owned()),
+                asn: Some(1136),
+                org: Some("KPN B.V.".to_owned()),
+            })
+        );
+        let ip_api = br#"{
+            "status": "success",
+            "country": "Netherlands",
+            "countryCode": "NL",
+            "region": "NH",
+            "regionName": "North Holland",
+            "city": "Amsterdam",
+            "isp": "KPN B.V.",
+            "org": "",
+            "as": "AS1136 KPN B.V.",
+            "query": "192.0.2.1"
+        }"#;
+        assert_eq!(
+            MetadataFormat::IpApi.parse(ip_api),
+            Some(Metadata {
+                country: Some("NL".to_owned()),
+                region: Some("North Holland".to_owned()),
+                city: Some("Amsterdam".to_owned()),
+                asn: Some(1136),
+                org: Some("KPN B.V.".to_owned()),
+            })
+        );
+        let ifconfig_co = br#"{
+            "ip": "192.0.2.1",
+            "ip_decimal": 3221225985,
+            "country": "Netherlands",
+            "country_iso": "NL",
+            "country_eu": true,
+            "region_name": "North Holland",
+            "city": "Amsterdam",
+            "asn": "AS1136",
+            "asn_org": "KPN B.V."
+        }"#;
+        assert_eq!(
+            MetadataFormat::IfconfigCo.parse(ifconfig_co),
+            Some(Metadata {
+                country: Some("NL".to_owned()),
+                region: Some("North Holland".to_owned()),
+                city: Some("Amsterdam".to_owned()),
+                asn: Some(1136),
+                org: Some("KPN B.V.".to_owned()),
+            })
+        );
+        assert_eq!(
+            MetadataFormat::IpInfo.parse(br#"{"ip": "192.0.2.1"}"#),
+            Some(Metadata::default())
+        );
+        assert_eq!(MetadataFormat::IpInfo.parse(b"192.0.2.1"), None);
+    }
+
+    #[test]
+    fn as_org() {
+        assert_eq!(
+            parse_as_org("AS1136 KPN B.V."),
+            (Some(1136), Some("KPN B.V.".to_owned()))
+        );
+        assert_eq!(parse_as_org("AS1136"), (Some(1136), None));
+        assert_eq!(parse_as_org("AS1136 "), (Some(1136), None));
+        assert_eq!(
+            parse_as_org("KPN B.V."),
+            (None, Some("KPN B.V.".to_owned()))
+        );
+        assert_eq!(parse_as_org("ASN KPN"), (None, Some("ASN KPN".to_owned())));
+    }
+
+    #[test]
+    fn retry_after() {
+        let now = httpdate::parse_http_date("Sun, 18 Oct 2026 12:00:00 GMT").unwrap();
+        assert_eq!(
+            parse_retry_after(" 120 ", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Sun, 18 Oct 2026 12:01:30 GMT", now),
+            Some(Duration::from_secs(90))
+        );
+        assert_eq!(
+            parse_retry_after("Sat, 17 Oct 2026 12:00:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("-1", now), None);
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    #[test]
+    fn rate_limit() {
+        let limit = RateLimit::new(2, Duration::from_secs(10));
+        let now = Instant::now();
+        let mut bucket = Bucket::new(limit, now);
+        assert_eq!(bucket.take(limit, now), Ok(()));
+        assert_eq!(bucket.take(limit, now), Ok(()));
+        assert_eq!(bucket.take(limit, now), Err(Duration::from_secs(5)));
+        // Tokens refill at two per ten seconds, up to the limit.
+        let now = now + Duration::from_secs(3);
+        assert_eq!(bucket.take(limit, now), Err(Duration::from_secs(2)));
+        let now = now + Duration::from_secs(2);
+        assert_eq!(bucket.take(limit, now), Ok(()));
+        let now = now + Duration::from_secs(60);
+        assert_eq!(bucket.take(limit, now), Ok(()));
+        assert_eq!(bucket.take(limit, now), Ok(()));
+        assert!(bucket.take(limit, now).is_err());
+
+        // Periods too long for a retry delay do not overflow.
+        let limit = RateLimit::new(1, Duration::MAX);
+        let mut bucket = Bucket::new(limit, now);
+        assert_eq!(bucket.take(limit, now), Ok(()));
+        assert!(bucket.take(limit, now).is_err());
+        let limit = RateLimit::new(0, Duration::from_secs(1));
+        let mut bucket = Bucket::new(limit, now);
+        assert_eq!(bucket.take(limit, now), Err(Duration::from_secs(1)));
+    }
+
+    const ROOT: &[u8] = include_bytes!("../tests/data/root.pem");
+    const INTERMEDIATE: &[u8] = include_bytes!("../tests/data/intermediate.pem");
+    const LEAF: &[u8] = include_bytes!("../tests/data/leaf.pem");
+    const OTHER: &[u8] = include_bytes!("../tests/data/other.pem");
+    const IDENTITY: &[u8] = include_bytes!("../tests/data/identity.pem");
+
+    fn cert(pem: &[u8]) -> CertificateDer<'static> {
+        CertificateDer::from_pem_slice(pem).unwrap()
+    }
+
+    fn pin(pem: &[u8]) -> [u8; 32] {
+        let cert = cert(pem);
+        let cert = webpki::EndEntityCert::try_from(&cert).unwrap();
+        let digest = digest::digest(&digest::SHA256, &cert.subject_public_key_info());
+        digest.as_ref().try_into().unwrap()
+    }
+
+    /// Verifies `localhost` with the given certificates sent by the server.
+    fn verify(pins: &[&[u8]], sent: &[&[u8]]) -> Result<ServerCertVerified, rustls::Error> {
+        let tls = pins.iter().fold(
+            Tls::new()
+                .with_native_roots(false)
+                .with_root_certificates_pem(ROOT)
+                .unwrap(),
+            |tls, pem| tls.with_pinned_key(pin(pem)),
+        );
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let verifier = tls.verifier(&provider).unwrap();
+        let intermediates: Vec<_> = sent.iter().map(|pem| cert(pem)).collect();
+        // 2030-01-01, within the validity of the test certificates.
+        let now = UnixTime::since_unix_epoch(Duration::from_secs(1_893_456_000));
+        verifier.verify_server_cert(
+            &cert(LEAF),
+            &intermediates,
+            &ServerName::try_from("localhost").unwrap(),
+            &[],
+            now,
+        )
+    }
+
+    #[test]
+    fn pinned_keys() {
+        // The pin of the root, as computed with the command in the docs.
+        assert_eq!(
+            pin(ROOT),
+            [
+                0xc4, 0x1c, 0xdb, 0xaf, 0x1b, 0xde, 0xb2, 0x86, 0xf7, 0x0f, 0x57, 0xb2, 0x2e, 0xf4,
+                0x5e, 0x6d, 0x7e, 0x73, 0xbc, 0x95, 0x5b, 0x15, 0x53, 0xf1, 0x84, 0x07, 0x60, 0x70,
+                0xeb, 0xb0, 0x86, 0x39,
+            ]
+        );
+        assert!(verify(&[], &[INTERMEDIATE]).is_ok());
+        assert!(verify(&[LEAF], &[INTERMEDIATE]).is_ok());
+        assert!(verify(&[INTERMEDIATE], &[INTERMEDIATE]).is_ok());
+        assert!(verify(&[ROOT], &[INTERMEDIATE]).is_ok());
+        assert!(verify(&[OTHER, ROOT], &[INTERMEDIATE]).is_ok());
+        assert!(verify(&[OTHER], &[INTERMEDIATE]).is_err());
+        // A pinned certificate sent alongside the chain is not on its path.
+        assert!(verify(&[OTHER], &[INTERMEDIATE, OTHER]).is_err());
+        // Pins do not replace verifying the chain.
+        assert!(verify(&[LEAF], &[]).is_err());
+    }
+
+    #[test]
+    fn der_sequences() {
+        assert_eq!(der_sequence(&[]), [0x30, 0x00]);
+        let der = der_sequence(&[0xaa; 127]);
+        assert_eq!((&der[..2], der.len()), (&[0x30, 0x7f][..], 129));
+        let der = der_sequence(&[0xaa; 128]);
+        assert_eq!((&der[..3], der.len()), (&[0x30, 0x81, 0x80][..], 131));
+        let der = der_sequence(&[0xaa; 300]);
+        assert_eq!((&der[..4], der.len()), (&[0x30, 0x82, 0x01, 0x2c][..], 304));
+    }
+
+    #[test]
+    fn identity_pem() {
+        let tls = Tls::new().with_identity_pem(IDENTITY).unwrap();
+        let identity = tls.identity.as_ref().unwrap();
+        assert_eq!(identity.chain, [cert(IDENTITY)]);
+        assert!(tls
+            .client_config()
+            .unwrap()
+            .client_auth_cert_resolver
+            .has_certs());
+
+        // The certificate and key are both required.
+        let key = IDENTITY
+            .windows(17)
+            .position(|window| window == b"BEGIN PRIVATE KEY")
+            .unwrap();
+        let (cert_pem, key_pem) = IDENTITY.split_at(key - "-----".len());
+        assert!(Tls::new().with_identity_pem(cert_pem).is_err());
+        assert!(Tls::new().with_identity_pem(key_pem).is_err());
+        assert!(Tls::new().with_identity_pem(b"not a pem").is_err());
+    }
+
+    #[test]
+    fn tls_clients() {
+        let tls = Tls::new()
+            .with_native_roots(false)
+            .with_root_certificates_pem(ROOT)
+            .unwrap();
+        let resolver = Resolver::new("https://localhost", ExtractMethod::PlainText)
+            .with_proxy(Proxy::Direct)
+            .with_tls(tls.clone());
+        resolver.client(Version::V4).unwrap();
+        resolver.client(Version::V4).unwrap();
+        resolver.client(Version::Any).unwrap();
+        assert_eq!(tls.clients.lock().len(), 2);
+        // Changing the settings starts over.
+        let tls = tls.with_pinned_key(pin(ROOT));
+        assert_eq!(tls.clients.lock().len(), 0);
+    }
+
+    #[test]
+    fn regex() {
+        let method =
+            ExtractMethod::Regex(Regex::new(r"Current IP Address: (?P<ip>[0-9.]+)").unwrap());
+        let body = b"<body>Current IP Address: 192.0.2.1</body>";
+        assert_eq!(
+            parse_body(body, &method).unwrap(),
+            "192.0.2.1".parse::<IpAddr>().unwrap()
+        );
+        assert!(parse_body(b"<body>192.0.2.1</body>", &method).is_err());
+    }
+
+    #[test]
+    fn content_types() {
+        let check = |content_type: Option<&str>, method| {
+            let mut headers = HeaderMap::new();
+            if let Some(content_type) = content_type {
+                headers.insert(CONTENT_TYPE, HeaderValue::from_str(content_type).unwrap());
+            }
+            check_content_type(&headers, &method)
+        };
+        assert!(check(Some("text/plain"), ExtractMethod::PlainText).is_ok());
+        assert!(check(Some("text/plain; charset=utf-8"), ExtractMethod::PlainText).is_ok());
+        assert!(check(Some(" Text/Plain ;charset=utf-8"), ExtractMethod::PlainText).is_ok());
+        assert!(check(Some("APPLICATION/JSON"), ExtractMethod::ExtractJsonIpField).is_ok());
+        assert!(matches!(
+            check(Some("text/html; charset=utf-8"), ExtractMethod::PlainText),
+            Err(Error::ContentType(Some(content_type))) if content_type == "text/html; charset=utf-8"
+        ));
+        assert!(matches!(
+            check(Some("text/plainer"), ExtractMethod::PlainText),
+            Err(Error::ContentType(_))
+        ));
+        assert!(matches!(
+            check(None, ExtractMethod::ExtractJsonIpField),
+            Err(Error::ContentType(None))
+        ));
+        // Methods that do not depend on the format accept anything.
+        assert!(check(Some("text/html"), ExtractMethod::FindIp).is_ok());
+        assert!(check(None, ExtractMethod::FindIp).is_ok());
+    }
+
+    /// Reads the body of a raw HTTP response served to a single request.
+    async fn body(response: &'static str, max_len: usize) -> Result<Vec<u8>, Error> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request).await;
+            let _ = stream.write_all(response.as_bytes()).await;
+        });
+        let client = reqwest::Client::builder().no_proxy().build().unwrap();
+        let response = client.get(url).send().await.unwrap();
+        read_body(response, max_len).await
+    }
+
+    #[tokio::test]
+    async fn body_len() {
+        let response = "HTTP/1.1 200 OK\r\nContent-Length: 9\r\n\r\n192.0.2.1";
+        assert_eq!(body(response, 9).await.unwrap(), b"192.0.2.1");
+        assert!(matches!(
+            body(response, 8).await,
+            Err(Error::BodyTooLarge(8))
+        ));
+
+        // Announced lengths are rejected before reading the body.
+        let response = "HTTP/1.1 200 OK\r\nContent-Length: 1000\r\nConnection: close\r\n\r\n";
+        assert!(matches!(
+            body(response, 999).await,
+            Err(Error::BodyTooLarge(999))
+        ));
+
+        let response = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                        5\r\n192.0\r\n4\r\n.2.1\r\n0\r\n\r\n";
+        assert_eq!(body(response, 9).await.unwrap(), b"192.0.2.1");
+        assert!(matches!(
+            body(response, 8).await,
+            Err(Error::BodyTooLarge(8))
+        ));
+    }
+
+    #[test]
+    fn redacted_debug() {
+        let resolver = Resolver::new(
+            "https://ipinfo.io/json?token=secret-query",
+            ExtractMethod::ExtractJsonIpField,
+        )
+        .with_headers(vec![(
+            "Authorization".into(),
+            "Bearer secret-header".into(),
+        )])
+        .with_query(vec![("token".into(), "secret-param".into())])
+        .with_proxy_auth("user", "secret-password");
+        let debug = format!("{resolver:?}");
+        assert!(debug.contains("\"https://ipinfo.io/json\""), "{debug}");
+        assert!(debug.contains("\"Authorization\": <redacted>"), "{debug}");
+        assert!(debug.contains("\"token\": <redacted>"), "{debug}");
+        assert!(debug.contains("(\"user\", <redacted>)"), "{debug}");
+        assert!(!debug.contains("secret"), "{debug}");
+        assert_eq!(without_query("https://ipinfo.io/"), "https://ipinfo.io/");
+    }
+
+    #[test]
+    fn headers() {
+        let ip = |addr: &str| addr.parse::<IpAddr>().unwrap();
+        let headers = |values: &[&'static str]| {
+            let mut headers = HeaderMap::new();
+            for value in values {
+                headers.append("x-forwarded-for", HeaderValue::from_static(value));
+            }
+            headers
+        };
+
+        let forwarded = headers(&["192.0.2.1, 198.51.100.7,2001:db8::1"]);
+        assert_eq!(
+            parse_header(&forwarded, "X-Forwarded-For", true).unwrap(),
+            ip("192.0.2.1")
+        );
+        let spaced = headers(&["  2001:db8::1 ,192.0.2.1"]);
+        assert_eq!(
+            parse_header(&spaced, "x-forwarded-for", true).unwrap(),
+            ip("2001:db8::1")
+        );
+        // Entries that are not addresses, such as obfuscated identifiers, are
+        // skipped.
+        let unknown = headers(&["unknown, _hidden,192.0.2.1"]);
+        assert_eq!(
+            parse_header(&unknown, "x-forwarded-for", true).unwrap(),
+            ip("192.0.2.1")
+        );
+        // Header lines are read in order, as if joined into a single list.
+        let lines = headers(&["unknown", "198.51.100.7, 192.0.2.1", "192.0.2.2"]);
+        assert_eq!(
+            parse_header(&lines, "x-forwarded-for", true).unwrap(),
+            ip("198.51.100.7")
+        );
+        let invalid = headers(&["unknown", ""]);
+        assert!(parse_header(&invalid, "x-forwarded-for", true).is_err());
+        assert!(parse_header(&HeaderMap::new(), "x-forwarded-for", true).is_err());
+
+        // Otherwise the first value is the address.
+        let single = headers(&[" 192.0.2.1 "]);
+        assert_eq!(
+            parse_header(&single, "x-forwarded-for", false).unwrap(),
+            ip("192.0.2.1")
+        );
+        let lines = headers(&["2001:db8::1", "192.0.2.1"]);
+        assert_eq!(
+            parse_header(&lines, "x-forwarded-for", false).unwrap(),
+            ip("2001:db8::1")
+        );
+        assert!(parse_header(&forwarded, "x-forwarded-for", false).is_err());
+        assert!(parse_header(&unknown, "x-forwarded-for", false).is_err());
+        assert!(parse_header(&HeaderMap::new(), "x-forwarded-for", false).is_err());
+    }
+
+    const TRACE: &[u8] = b"fl=29f1
+h=one.one.one.one
+ip=192.0.2.1
+ts=1760000000.123
+visit_scheme=https
+uag=public-ip
+colo=AMS
+sliver=none
+http=http/2
+loc=NL
+tls=TLSv1.3
+sni=plaintext
+warp=off
+gateway=off
+";
+
+    fn trace_details(url: &str, body: &[u8]) -> Details {
+        Details {
+            url: url.parse().unwrap(),
+            server: None,
+            proxy: None,
+            method: ExtractMethod::KeyValue("ip".into()),
+            key_values: parse_key_values(body)
+                .map(|(key, value)| (key.to_owned(), value.to_owned()))
+                .collect(),
+            metadata: None,
+        }
+    }
+
+    #[test]
+    fn key_values() {
+        let pairs: Vec<_> = parse_key_values(TRACE).collect();
+        assert_eq!(pairs.len(), 14);
+        assert_eq!(pairs[2], ("ip", "192.0.2.1"));
+        let pairs: Vec<_> = parse_key_values(b" colo = AMS \r\nnot a pair\n\nuag=a=b").collect();
+        assert_eq!(pairs, [("colo", "AMS"), ("uag", "a=b")]);
+
+        let ip = ExtractMethod::KeyValue("ip".into());
+        assert_eq!(
+            parse_body(TRACE, &ip).unwrap(),
+            "192.0.2.1".parse::<IpAddr>().unwrap()
+        );
+        assert!(parse_body(b"colo=AMS\nloc=NL\n", &ip).is_err());
+        assert!(parse_body(b"ip=unknown\n", &ip).is_err());
+        // Only the value of the given key is parsed.
+        assert!(parse_body(TRACE, &ExtractMethod::KeyValue("h".into())).is_err());
+        assert!(parse_body(b"h=192.0.2.1\n", &ip).is_err());
+    }
+
+    #[test]
+    fn cloudflare_traces() {
+        let details = trace_details("https://1.1.1.1/cdn-cgi/trace", TRACE);
+        assert_eq!(details.key_value("ip"), Some("192.0.2.1"));
+        assert_eq!(details.key_value("sni"), Some("plaintext"));
+        assert_eq!(details.key_value("rbi"), None);
+        let trace = details.cloudflare_trace().unwrap();
+        assert_eq!(trace.colo(), "AMS");
+        assert_eq!(trace.loc(), "NL");
+        assert_eq!(trace.warp(), Some("off"));
+        assert_eq!(trace.tls(), Some("TLSv1.3"));
+
+        // Older traces and plain HTTP may lack the optional keys.
+        let details = trace_details(
+            "http://1.1.1.1/cdn-cgi/trace",
+            b"ip=192.0.2.1\ncolo=AMS\nloc=NL\n",
+        );
+        let trace = details.cloudflare_trace().unwrap();
+        assert_eq!((trace.warp(), trace.tls()), (None, None));
+
+        let details = trace_details("https://1.1.1.1/cdn-cgi/trace", b"ip=192.0.2.1\nloc=NL\n");
+        assert_eq!(details.cloudflare_trace(), None);
+        let details = trace_details("https://example.com/trace", TRACE);
+        assert_eq!(details.cloudflare_trace(), None);
+        assert_eq!(details.key_value("colo"), Some("AMS"));
+    }
+}
@@ -31,6 +31,8 @@
 #![allow(clippy::needless_pass_by_value)]
 
 mod error;
+#[cfg(any(feature = "dns-resolver", feature = "http-resolver"))]
+mod extractor;
 
 /// DNS resolver support.
 #[cfg(feature = "dns-resolver")]
@@ -58,6 +60,12 @@ use tracing::trace_span;
 use tracing_futures::Instrument;
 
 pub use crate::error::Error;
+#[cfg(any(feature = "dns-resolver", feature = "http-resolver"))]
+#[cfg_attr(
+    docsrs,
+    doc(cfg(any(feature = "dns-resolver", feature = "http-resolver")))
+)]
+pub use crate::extractor::Extractor;
 
 /// The details of a resolution.
 ///